@@ -0,0 +1,160 @@
+//! Compares the arena octree against a boxed octree laid out like the old `TreeBody` tree, on the
+//! surface of a sphere. Run with `cargo bench --bench octree`.
+
+#[allow(dead_code)]
+#[path = "../src/octree.rs"]
+mod octree;
+
+use nalgebra::Vector3;
+use octree::Octree;
+use std::time::Instant;
+
+/// Same size as a voxel's color & normal
+type Payload = ([u8; 4], [f32; 3]);
+
+enum Boxed<T> {
+    Empty,
+    Leaf(T),
+    Branch(Box<[Boxed<T>; 8]>),
+}
+
+/// Boxed octree spanning 2ᵈᵉᵖᵗʰ voxels from the origin
+struct BoxedTree<T> {
+    depth: u32,
+    root: Boxed<T>,
+    branches: usize,
+}
+
+impl<T> BoxedTree<T> {
+    fn new(depth: u32) -> Self {
+        Self {
+            depth,
+            root: Boxed::Empty,
+            branches: 0,
+        }
+    }
+
+    fn index(pos: Vector3<isize>, depth: u32) -> usize {
+        ((pos.x >> depth & 1) << 2 | (pos.y >> depth & 1) << 1 | (pos.z >> depth & 1)) as usize
+    }
+
+    fn insert(&mut self, pos: Vector3<isize>, value: T) {
+        let mut node = &mut self.root;
+        for depth in (0..self.depth).rev() {
+            if let Boxed::Empty = node {
+                *node = Boxed::Branch(Box::new([(); 8].map(|_| Boxed::Empty)));
+                self.branches += 1;
+            }
+            node = match node {
+                Boxed::Branch(children) => &mut children[Self::index(pos, depth)],
+                _ => unreachable!(),
+            };
+        }
+        *node = Boxed::Leaf(value);
+    }
+
+    fn get(&self, pos: Vector3<isize>) -> Option<&T> {
+        let mut node = &self.root;
+        for depth in (0..self.depth).rev() {
+            node = match node {
+                Boxed::Branch(children) => &children[Self::index(pos, depth)],
+                _ => return None,
+            };
+        }
+        match node {
+            Boxed::Leaf(value) => Some(value),
+            _ => None,
+        }
+    }
+
+    fn count(&self) -> usize {
+        fn walk<T>(node: &Boxed<T>) -> usize {
+            match node {
+                Boxed::Empty => 0,
+                Boxed::Leaf(_) => 1,
+                Boxed::Branch(children) => children.iter().map(walk).sum(),
+            }
+        }
+        walk(&self.root)
+    }
+
+    fn memory_usage(&self) -> usize {
+        self.branches * std::mem::size_of::<[Boxed<T>; 8]>()
+    }
+}
+
+fn sphere_shell(radius: isize) -> Vec<Vector3<isize>> {
+    let mut res = vec![];
+    let r = radius as f32;
+    for x in 0..2 * radius {
+        for y in 0..2 * radius {
+            for z in 0..2 * radius {
+                let d = Vector3::new(x, y, z)
+                    .map(|c| c as f32 - r + 0.5)
+                    .magnitude();
+                if (d - r + 1.).abs() < 0.5 {
+                    res.push(Vector3::new(x, y, z));
+                }
+            }
+        }
+    }
+    res
+}
+
+fn time<R>(name: &str, f: impl FnOnce() -> R) -> R {
+    let now = Instant::now();
+    let res = f();
+    println!(
+        "  {name:<10} {:>10.2}ms",
+        now.elapsed().as_secs_f64() * 1000.
+    );
+    res
+}
+
+fn main() {
+    let radius = 256;
+    let voxels = sphere_shell(radius);
+    let depth = (2 * radius as usize).next_power_of_two().trailing_zeros();
+    let payload: Payload = ([255; 4], [0.; 3]);
+    println!("{} voxels on a sphere of radius {radius}", voxels.len());
+
+    println!("arena octree");
+    let mut arena = Octree::<Payload>::new();
+    time("insert", || {
+        for v in &voxels {
+            arena.insert(*v, payload);
+        }
+    });
+    let found = time("get", || {
+        voxels.iter().filter(|v| arena.get(**v).is_some()).count()
+    });
+    let iterated = time("iterate", || arena.iter().count());
+    let memory = arena.memory_usage();
+    time("remove", || {
+        for v in &voxels {
+            arena.remove(*v);
+        }
+    });
+    assert_eq!(found, voxels.len());
+    assert_eq!(iterated, voxels.len());
+    assert!(arena.is_empty());
+    println!("  memory     {:>10.2}MiB", memory as f64 / (1 << 20) as f64);
+
+    println!("boxed octree");
+    let mut boxed = BoxedTree::<Payload>::new(depth);
+    time("insert", || {
+        for v in &voxels {
+            boxed.insert(*v, payload);
+        }
+    });
+    let found = time("get", || {
+        voxels.iter().filter(|v| boxed.get(**v).is_some()).count()
+    });
+    let iterated = time("iterate", || boxed.count());
+    assert_eq!(found, voxels.len());
+    assert_eq!(iterated, voxels.len());
+    println!(
+        "  memory     {:>10.2}MiB",
+        boxed.memory_usage() as f64 / (1 << 20) as f64
+    );
+}
@@ -0,0 +1,156 @@
+use std::str::FromStr;
+
+use brickadia::save as brs;
+
+use crate::simplify::{cover_rectangle, scaled_pos};
+use crate::{asset_index, BrickType};
+
+/// A non-procedural brick asset with a fixed size
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct CatalogBrick {
+    pub asset: String,
+    /// Width & depth in studs, and height in plates, when unrotated
+    pub size: (isize, isize, isize),
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum ParseCatalogBrickError {
+    #[error("Expected <asset>:<width>x<depth>x<height>, got {0:?}")]
+    Format(String),
+    #[error("Invalid brick size {0:?}: {1}")]
+    Size(String, std::num::ParseIntError),
+    #[error("Brick sizes must be positive, got {0:?}")]
+    NonPositive(String),
+}
+
+impl FromStr for CatalogBrick {
+    type Err = ParseCatalogBrickError;
+
+    /// Parse from `<asset>:<width>x<depth>x<height>`, ex. `B_2x4_Brick:2x4x3`
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (asset, size) = s
+            .split_once(':')
+            .ok_or_else(|| ParseCatalogBrickError::Format(s.to_owned()))?;
+        let dims = size
+            .split('x')
+            .map(|d| {
+                d.parse::<isize>()
+                    .map_err(|e| ParseCatalogBrickError::Size(size.to_owned(), e))
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        match dims[..] {
+            [w, d, h] if w > 0 && d > 0 && h > 0 => Ok(Self {
+                asset: asset.to_owned(),
+                size: (w, d, h),
+            }),
+            [_, _, _] => Err(ParseCatalogBrickError::NonPositive(size.to_owned())),
+            _ => Err(ParseCatalogBrickError::Format(s.to_owned())),
+        }
+    }
+}
+
+pub fn default_catalog() -> Vec<CatalogBrick> {
+    [
+        ("B_2x4_Brick", (2, 4, 3)),
+        ("B_2x2_Brick", (2, 2, 3)),
+        ("B_1x4_Brick", (1, 4, 3)),
+        ("B_1x2_Brick", (1, 2, 3)),
+        ("B_1x1_Brick", (1, 1, 3)),
+        ("B_2x4F_Plate", (2, 4, 1)),
+        ("B_2x2F_Plate", (2, 2, 1)),
+        ("B_1x2F_Plate", (1, 2, 1)),
+        ("B_1x1F_Plate", (1, 1, 1)),
+    ]
+    .into_iter()
+    .map(|(asset, size)| CatalogBrick {
+        asset: asset.to_owned(),
+        size,
+    })
+    .collect()
+}
+
+/// Replace the procedural plate bricks in `save_data.bricks[start..]` with bricks from the
+/// catalog. Each brick is filled with layers of the tallest catalog bricks that fit, and each
+/// layer is packed with the largest footprints first.
+pub fn fill_catalog(save_data: &mut brs::SaveData, start: usize, catalog: &[CatalogBrick]) {
+    let scales = BrickType::Default.voxel_size();
+    let (sx, sy, sz) = scales;
+
+    let asset_indices = catalog
+        .iter()
+        .map(|c| asset_index(save_data, &c.asset))
+        .collect::<Vec<_>>();
+
+    let mut heights = catalog.iter().map(|c| c.size.2).collect::<Vec<_>>();
+    heights.sort_unstable_by(|a, b| b.cmp(a));
+    heights.dedup();
+
+    let mut unfilled = 0;
+    for brick in save_data.bricks.split_off(start) {
+        let (w, d, h) = match brick.size {
+            brs::Size::Procedural(w, d, h) => (w as isize / sx, d as isize / sy, h as isize / sz),
+            _ => {
+                save_data.bricks.push(brick);
+                continue;
+            }
+        };
+        let (x, y, z) = (
+            (brick.position.0 as isize - w * sx) / (2 * sx),
+            (brick.position.1 as isize - d * sy) / (2 * sy),
+            (brick.position.2 as isize - h * sz) / (2 * sz),
+        );
+
+        let mut lz = 0;
+        while lz < h {
+            let lh = match heights.iter().find(|lh| **lh <= h - lz) {
+                Some(lh) => *lh,
+                None => {
+                    unfilled += w * d * (h - lz);
+                    break;
+                }
+            };
+
+            let mut layer = catalog
+                .iter()
+                .enumerate()
+                .filter(|(_, c)| c.size.2 == lh)
+                .collect::<Vec<_>>();
+            layer.sort_by_key(|(_, c)| -(c.size.0 * c.size.1));
+            let footprints = layer
+                .iter()
+                .map(|(_, c)| (c.size.0, c.size.1))
+                .collect::<Vec<_>>();
+
+            let pieces = cover_rectangle(w, d, &footprints);
+            let covered = pieces
+                .iter()
+                .map(|p| footprints[p.2].0 * footprints[p.2].1)
+                .sum::<isize>();
+            unfilled += (w * d - covered) * lh;
+            for (px, py, i, rotated) in pieces {
+                let (ci, _) = layer[i];
+                let (fw, fd) = match rotated {
+                    true => (footprints[i].1, footprints[i].0),
+                    false => footprints[i],
+                };
+                save_data.bricks.push(brs::Brick {
+                    asset_name_index: asset_indices[ci],
+                    size: brs::Size::Empty,
+                    position: scaled_pos(scales, (fw, fd, lh), (x + px, y + py, z + lz)),
+                    rotation: match rotated {
+                        true => brs::Rotation::Deg90,
+                        false => brs::Rotation::Deg0,
+                    },
+                    ..brick.clone()
+                });
+            }
+            lz += lh;
+        }
+    }
+
+    if unfilled > 0 {
+        tracing::warn!(
+            "A volume of {unfilled} 1x1 plates could not be filled with the given catalog bricks"
+        );
+    }
+}
@@ -4,7 +4,17 @@ use clap::Parser;
 use lazy_static::lazy_static;
 use uuid::Uuid;
 
-use crate::{BrickType, ConversionOptions, LogFormat, Material};
+use crate::{
+    catalog::{self, CatalogBrick},
+    color::{self, ColorAveraging},
+    load::{PointColumns, RepairOptions},
+    rampify::RampOptions,
+    simplify::MergeStrategy,
+    store::VoxelStorage,
+    texture::{Sampler, TextureFilter, WrapMode},
+    voxelize::{Connectivity, VoxelizeOptions},
+    AlphaMode, BrickType, ConversionOptions, LogFormat, Material,
+};
 
 lazy_static! {
     pub static ref BUILD_DIR: PathBuf = {
@@ -46,6 +56,51 @@ pub struct Cli {
     #[clap(short, long, default_value_t = 1.00)]
     /// Scale factor from the input model to the output save
     pub scale: f32,
+    #[clap(long, value_parser, default_value = "linear")]
+    /// Color space used when averaging the colors of merged voxels
+    pub color_averaging: ColorAveraging,
+    #[clap(long, value_parser, default_value = "nearest")]
+    /// Filtering used when sampling textures. Use "area" to avoid aliasing when converting high resolution textures to few voxels.
+    pub texture_filter: TextureFilter,
+    #[clap(long, value_parser, default_value = "repeat")]
+    /// How texture coordinates outside of the texture are handled
+    pub texture_wrap: WrapMode,
+    #[clap(long, default_value_t = 1, value_parser = clap::value_parser!(u32).range(1..=256))]
+    /// Number of texture samples to average over each triangle in each voxel. Values above 1 give more faithful colors at low resolutions.
+    pub supersample: u32,
+    #[clap(long, default_value_t = 0.0)]
+    /// Texels with alpha at or below this value (0-1) are treated as empty
+    pub alpha_cutoff: f32,
+    #[clap(long, value_parser, default_value = "blend")]
+    /// How partially transparent texels are converted. Use "glass" to place them as glass bricks, keeping their alpha.
+    pub alpha_mode: AlphaMode,
+    #[clap(long)]
+    /// Repair broken meshes before voxelizing: drop degenerate faces, weld nearby vertices, and close small holes
+    pub repair: bool,
+    #[clap(long, default_value_t = 1e-5)]
+    /// Distance, in model units, within which vertices are welded by --repair
+    pub weld_distance: f32,
+    #[clap(long, default_value_t = 8)]
+    /// Largest hole, in edges, closed by --repair
+    pub max_hole_edges: usize,
+    #[clap(long, value_parser, default_value = "conservative")]
+    /// Which voxels make up a triangle's surface. Use "thin" to keep diagonal walls one voxel thick.
+    pub connectivity: Connectivity,
+    #[clap(long, value_name = "VOXELS", value_parser = clap::value_parser!(u32).range(1..))]
+    /// Draw the model's lines & points as voxels, this many voxels across. They're skipped otherwise.
+    pub line_thickness: Option<u32>,
+    #[clap(long, default_value_t = 1, value_parser = clap::value_parser!(u32).range(1..))]
+    /// Fewest points a voxel of a point cloud (.xyz, .pts, .pcd) needs to be kept. Raise it to discard stray points from noisy scans.
+    pub min_points: u32,
+    #[clap(long, value_parser, default_value = "auto")]
+    /// What follows x y z on each line of an .xyz or .pts point cloud. Set it if colors are read as normals or the other way around.
+    pub point_columns: PointColumns,
+    #[clap(long, value_parser, default_value = "octree")]
+    /// Container voxels are kept in. Use "grid" for long, thin models, which would need a very large octree.
+    pub voxel_storage: VoxelStorage,
+    #[clap(long, value_name = "MiB")]
+    /// Convert each model in spatial tiles expected to fit in this much memory, for models too large to voxelize at once. Bricks don't merge across tile seams. A quarter of the budget holds bricks waiting to be written; past that, they're written to numbered parts next to the output (model.1.brs, model.2.brs, ...). Inputs written to a directory are converted one at a time.
+    pub memory_budget: Option<u64>,
     #[clap(long, value_parser, default_value = "plastic")]
     /// Material type for output bricks
     pub material: Material,
@@ -65,6 +120,34 @@ pub struct Cli {
 }
 
 impl Cli {
+    pub fn voxelize_options(&self) -> VoxelizeOptions {
+        VoxelizeOptions {
+            averaging: self.color_averaging,
+            sampler: Sampler {
+                filter: self.texture_filter,
+                wrap: self.texture_wrap,
+            },
+            samples: self.supersample,
+            alpha_cutoff: color::ftoi(self.alpha_cutoff.clamp(0., 1.)),
+            alpha_mode: self.alpha_mode,
+            connectivity: self.connectivity,
+            storage: self.voxel_storage,
+            line_thickness: self.line_thickness,
+            min_points: self.min_points,
+        }
+    }
+
+    pub fn repair_options(&self) -> RepairOptions {
+        match self.repair {
+            true => RepairOptions {
+                drop_degenerate: true,
+                weld_distance: Some(self.weld_distance),
+                max_hole_edges: self.max_hole_edges,
+            },
+            false => RepairOptions::default(),
+        }
+    }
+
     pub fn inputs(&self) -> &[PathBuf] {
         match self.command {
             None => &[],
@@ -124,15 +207,63 @@ pub enum Command {
         #[clap(long, default_value_t = 200)]
         /// Maximum merges to perform when simplifying
         max_merge: u32,
+        #[clap(long, value_parser, default_value = "greedy")]
+        /// How voxels are merged into bricks by lossless simplification. "optimal" produces fewer bricks, but takes longer.
+        merge_strategy: MergeStrategy,
+        #[clap(long, requires = "lossy")]
+        /// Only merge voxels whose colors are within this distance (in OKLab, 0 to 1) of the average color of their brick when simplifying lossily. Lower values keep more detail; ex. 0.05.
+        color_tolerance: Option<f32>,
+        #[clap(long)]
+        /// Split bricks into standard heights (plates & whole bricks) and footprints (1x1, 1x2, 2x2, 2x4, ...), for builds that look like real brick construction. Only affects "default" & "tiles" bricktypes.
+        standard_sizes: bool,
+        #[clap(long)]
+        /// Build only from non-procedural catalog bricks, for servers that restrict procedural bricks. Requires the "default" bricktype.
+        catalog: bool,
+        #[clap(long = "catalog-brick", value_parser)]
+        /// Catalog brick available to --catalog, as <asset>:<width>x<depth>x<height> in studs & plates (ex. B_2x4_Brick:2x4x3). May be repeated. Defaults to common bricks & plates.
+        catalog_bricks: Vec<CatalogBrick>,
+        #[clap(long)]
+        /// Replace microbricks on sloped surfaces with microwedges facing along the surface. Only affects the "microbricks" bricktype.
+        smooth: bool,
+        #[clap(long)]
+        /// Turn the top of each brick towards the outside of the model, so tiles on walls & overhangs show their smooth side. Only affects "default" & "tiles" bricktypes.
+        orient_to_surface: bool,
         #[clap(value_parser)]
-        /// Input files. If empty, launch the GUI.
+        /// Input files: .obj models, or .xyz, .pts & ASCII .pcd point clouds. If empty, launch the GUI.
         inputs: Vec<PathBuf>,
     },
     #[clap()]
     /// Convert & apply Wrapperup's plate-rampifier
     Rampify {
+        #[clap(long)]
+        /// Match model colors to the default Brickadia colorset
+        default_colorset: bool,
+        #[clap(short, long, value_parser, default_value = "default")]
+        /// Type of bricks to rampify with. "microbricks" gives finer slopes, using microwedges in place of wedges, and microwedges on a one-voxel microbrick lip in place of ramps; "tiles" fills with tiles.
+        bricktype: BrickType,
+        #[clap(long)]
+        /// Don't generate ramps on upward-facing slopes
+        no_floor_ramps: bool,
+        #[clap(long)]
+        /// Don't generate ramps on downward-facing slopes
+        no_ceiling_ramps: bool,
+        #[clap(long, value_name = "VOXELS", default_value_t = 1)]
+        /// Replace ramps shorter than this along their slope with bricks
+        min_ramp_length: u32,
+        #[clap(long, value_name = "VOXELS")]
+        /// Replace ramps longer than this along their slope with bricks
+        max_ramp_length: Option<u32>,
+        #[clap(long)]
+        /// Replace the wedges placed alongside ramps with bricks
+        no_wedges: bool,
+        #[clap(long, default_value_t = 64)]
+        /// Largest size, in plates along each axis, of the bricks that fill the space left by ramps
+        max_fill: u32,
+        #[clap(long)]
+        /// Turn each ramp & wedge so its slope faces the outside of the model along the surface it covers
+        orient_to_surface: bool,
         #[clap(value_parser)]
-        /// Input files. If empty, launch the GUI.
+        /// Input files: .obj models, or .xyz, .pts & ASCII .pcd point clouds. If empty, launch the GUI.
         inputs: Vec<PathBuf>,
     },
 }
@@ -144,6 +275,13 @@ impl Default for Command {
             default_colorset: false,
             bricktype: BrickType::Microbricks,
             max_merge: 200,
+            merge_strategy: MergeStrategy::Greedy,
+            color_tolerance: None,
+            standard_sizes: false,
+            catalog: false,
+            catalog_bricks: vec![],
+            smooth: false,
+            orient_to_surface: false,
             inputs: vec![],
         }
     }
@@ -157,14 +295,58 @@ impl Command {
                 default_colorset,
                 bricktype,
                 max_merge,
+                merge_strategy,
+                color_tolerance,
+                standard_sizes,
+                catalog,
+                smooth,
+                orient_to_surface,
                 ..
             } => ConversionOptions::Simplify {
                 lossless: !lossy,
                 match_default_colorset: *default_colorset,
                 bricktype: *bricktype,
                 max_merge: *max_merge,
+                merge_strategy: *merge_strategy,
+                color_tolerance: *color_tolerance,
+                standard_sizes: *standard_sizes,
+                catalog: *catalog,
+                smooth: *smooth,
+                orient_to_surface: *orient_to_surface,
+            },
+            Self::Rampify {
+                default_colorset,
+                bricktype,
+                no_floor_ramps,
+                no_ceiling_ramps,
+                min_ramp_length,
+                max_ramp_length,
+                no_wedges,
+                max_fill,
+                orient_to_surface,
+                ..
+            } => ConversionOptions::Rampify {
+                match_default_colorset: *default_colorset,
+                bricktype: *bricktype,
+                ramps: RampOptions {
+                    floor_ramps: !no_floor_ramps,
+                    ceiling_ramps: !no_ceiling_ramps,
+                    min_length: *min_ramp_length,
+                    max_length: *max_ramp_length,
+                    wedges: !no_wedges,
+                    max_fill: *max_fill,
+                },
+                orient_to_surface: *orient_to_surface,
             },
-            Self::Rampify { .. } => ConversionOptions::Rampify {},
+        }
+    }
+
+    pub fn catalog_bricks(&self) -> Vec<CatalogBrick> {
+        match self {
+            Self::Convert { catalog_bricks, .. } if !catalog_bricks.is_empty() => {
+                catalog_bricks.clone()
+            }
+            _ => catalog::default_catalog(),
         }
     }
 }
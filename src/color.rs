@@ -117,6 +117,128 @@ pub fn hsv_average(colors: &[Vector4<u8>]) -> Vector4<f32> {
     Vector4::<f32>::new(h_sum / n, s_sum / n, v_sum / n, a_sum / n)
 }
 
+/// Color space in which voxel colors are averaged together
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Hash, clap::ValueEnum)]
+pub enum ColorAveraging {
+    /// Average hue, saturation & value directly; hues on opposite sides of red average poorly
+    Hsv,
+    /// Average premultiplied-alpha linear RGB
+    Linear,
+    /// Average in the perceptual OKLab space
+    Oklab,
+}
+
+impl Default for ColorAveraging {
+    fn default() -> Self {
+        Self::Linear
+    }
+}
+
+pub fn srgb_to_linear(v: f32) -> f32 {
+    if v > 0.04045 {
+        ((v + 0.055) / 1.055).powf(2.4)
+    } else {
+        v / 12.92
+    }
+}
+
+pub fn linear_to_srgb(v: f32) -> f32 {
+    if v > 0.003_130_8 {
+        1.055 * v.powf(1. / 2.4) - 0.055
+    } else {
+        v * 12.92
+    }
+}
+
+pub fn rgb2linear(rgb: Vector4<u8>) -> Vector4<f32> {
+    Vector4::new(
+        srgb_to_linear(itof(rgb[0])),
+        srgb_to_linear(itof(rgb[1])),
+        srgb_to_linear(itof(rgb[2])),
+        itof(rgb[3]),
+    )
+}
+
+pub fn linear2rgb(linear: Vector4<f32>) -> Vector4<u8> {
+    Vector4::new(
+        ftoi(linear_to_srgb(linear[0].clamp(0., 1.))),
+        ftoi(linear_to_srgb(linear[1].clamp(0., 1.))),
+        ftoi(linear_to_srgb(linear[2].clamp(0., 1.))),
+        ftoi(linear[3].clamp(0., 1.)),
+    )
+}
+
+pub fn rgb2oklab(rgb: Vector4<u8>) -> Vector4<f32> {
+    let lin = rgb2linear(rgb);
+    let l = (0.412_221_47 * lin.x + 0.536_332_55 * lin.y + 0.051_445_995 * lin.z).cbrt();
+    let m = (0.211_903_5 * lin.x + 0.680_699_5 * lin.y + 0.107_396_96 * lin.z).cbrt();
+    let s = (0.088_302_46 * lin.x + 0.281_718_85 * lin.y + 0.629_978_7 * lin.z).cbrt();
+
+    Vector4::new(
+        0.210_454_26 * l + 0.793_617_8 * m - 0.004_072_047 * s,
+        1.977_998_5 * l - 2.428_592_2 * m + 0.450_593_7 * s,
+        0.025_904_037 * l + 0.782_771_77 * m - 0.808_675_77 * s,
+        lin.w,
+    )
+}
+
+pub fn oklab2rgb(lab: Vector4<f32>) -> Vector4<u8> {
+    let l = (lab.x + 0.396_337_78 * lab.y + 0.215_803_76 * lab.z).powi(3);
+    let m = (lab.x - 0.105_561_346 * lab.y - 0.063_854_17 * lab.z).powi(3);
+    let s = (lab.x - 0.089_484_18 * lab.y - 1.291_485_5 * lab.z).powi(3);
+
+    linear2rgb(Vector4::new(
+        4.076_741_7 * l - 3.307_711_6 * m + 0.230_969_94 * s,
+        -1.268_438 * l + 2.609_757_4 * m - 0.341_319_38 * s,
+        -0.004_196_086_3 * l - 0.703_418_6 * m + 1.707_614_7 * s,
+        lab.w,
+    ))
+}
+
+/// Perceptual distance between two OKLab colors, including their alpha
+pub fn oklab_distance(a: &Vector4<f32>, b: &Vector4<f32>) -> f32 {
+    (a - b).magnitude()
+}
+
+pub fn average(colors: &[Vector4<u8>], mode: ColorAveraging) -> Vector4<u8> {
+    weighted_average(colors.iter().map(|c| (*c, 1.)), mode)
+}
+
+/// Averages `(color, weight)` pairs. Colors are also weighted by their alpha so that
+/// mostly-transparent samples don't darken the result.
+pub fn weighted_average(
+    samples: impl IntoIterator<Item = (Vector4<u8>, f32)>,
+    mode: ColorAveraging,
+) -> Vector4<u8> {
+    let (into_space, from_space): (
+        fn(Vector4<u8>) -> Vector4<f32>,
+        fn(Vector4<f32>) -> Vector4<u8>,
+    ) = match mode {
+        ColorAveraging::Hsv => (rgb2hsv, hsv2rgb),
+        ColorAveraging::Linear => (rgb2linear, linear2rgb),
+        ColorAveraging::Oklab => (rgb2oklab, oklab2rgb),
+    };
+
+    let mut sum = Vector4::<f32>::zeros();
+    let mut total_weight = 0.;
+    for (c, weight) in samples {
+        let v = into_space(c);
+        let w = v.w * weight;
+        sum += Vector4::new(v.x * w, v.y * w, v.z * w, w);
+        total_weight += weight;
+    }
+
+    if sum.w <= 0. || total_weight <= 0. {
+        return Vector4::zeros();
+    }
+    from_space(Vector4::new(
+        sum.x / sum.w,
+        sum.y / sum.w,
+        sum.z / sum.w,
+        sum.w / total_weight,
+    ))
+}
+
 pub fn convert_colorset_to_hsv(colorset: &[brickadia::save::Color]) -> Vec<Vector4<f32>> {
     let mut converted_colorset = Vec::<Vector4<f32>>::with_capacity(colorset.len());
     for c in colorset {
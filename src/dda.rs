@@ -0,0 +1,85 @@
+use nalgebra::Vector3;
+
+/// Voxels crossed by the segment from `a` to `b` in order, found with a 3D DDA (Amanatides &
+/// Woo). Voxels span a unit cube from their position, and consecutive voxels share a face.
+pub fn traverse(a: Vector3<f32>, b: Vector3<f32>) -> Vec<Vector3<isize>> {
+    let mut voxel = a.map(|c| c.floor() as isize);
+    let last = b.map(|c| c.floor() as isize);
+    let d = b - a;
+    let step = (last - voxel).map(|c| c.signum());
+
+    // Fraction of the segment travelled when crossing the next boundary on each axis, and
+    // between boundaries
+    let mut t_max = Vector3::from_fn(|i, _| match step[i] {
+        0 => f32::INFINITY,
+        s => (voxel[i] as f32 + (s > 0) as isize as f32 - a[i]) / d[i],
+    });
+    let t_delta = d.map(|c| match c {
+        c if c != 0. => 1. / c.abs(),
+        _ => f32::INFINITY,
+    });
+
+    let steps = (last - voxel).abs().sum() as usize;
+    let mut res = Vec::with_capacity(steps + 1);
+    res.push(voxel);
+    for _ in 0..steps {
+        // axes already at the end are never stepped again, so rounding can't overshoot it
+        let axis = (0..3)
+            .filter(|i| voxel[*i] != last[*i])
+            .min_by(|i, j| t_max[*i].total_cmp(&t_max[*j]))
+            .unwrap();
+        voxel[axis] += step[axis];
+        t_max[axis] += t_delta[axis];
+        res.push(voxel);
+    }
+    res
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn check(a: Vector3<f32>, b: Vector3<f32>) -> Vec<Vector3<isize>> {
+        let voxels = traverse(a, b);
+        let first = a.map(|c| c.floor() as isize);
+        let last = b.map(|c| c.floor() as isize);
+        assert_eq!(voxels.len(), (last - first).abs().sum() as usize + 1);
+        assert_eq!(voxels[0], first);
+        assert_eq!(*voxels.last().unwrap(), last);
+        for pair in voxels.windows(2) {
+            assert_eq!(
+                (pair[1] - pair[0]).abs().sum(),
+                1,
+                "{pair:?} don't share a face"
+            );
+        }
+        voxels
+    }
+
+    #[test]
+    fn single_voxel() {
+        let voxels = check(Vector3::new(0.2, 0.3, 0.4), Vector3::new(0.8, 0.1, 0.9));
+        assert_eq!(voxels, vec![Vector3::new(0, 0, 0)]);
+    }
+
+    #[test]
+    fn axis_aligned() {
+        let voxels = check(Vector3::new(0.5, 0.5, 0.5), Vector3::new(4.5, 0.5, 0.5));
+        let expected = (0..5).map(|x| Vector3::new(x, 0, 0)).collect::<Vec<_>>();
+        assert_eq!(voxels, expected);
+    }
+
+    #[test]
+    fn diagonal_steps_one_axis_at_a_time() {
+        let voxels = check(Vector3::new(0.1, 0.2, 0.3), Vector3::new(3.7, 2.9, 5.4));
+        assert_eq!(voxels.len(), 3 + 2 + 5 + 1);
+        // the segment reaches a z boundary first
+        assert_eq!(voxels[1], Vector3::new(0, 0, 1));
+    }
+
+    #[test]
+    fn negative_directions() {
+        check(Vector3::new(2.5, -0.5, 1.2), Vector3::new(-3.2, -4.7, -2.9));
+        check(Vector3::new(-0.5, 7.25, 0.), Vector3::new(-0.5, -7.25, 0.));
+    }
+}
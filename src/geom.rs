@@ -1,2 +1,4 @@
-// mod polyhedron;
-// pub use polyhedron::*;
+mod polyhedron;
+mod triangulate;
+pub use polyhedron::*;
+pub use triangulate::*;
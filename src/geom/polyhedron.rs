@@ -1,104 +1,314 @@
-use std::{fmt::Debug, ops::{Add, Deref, DerefMut, Index, IndexMut, Range}, collections::HashMap};
+use std::{collections::HashMap, fmt::Debug, hash::Hash, ops::Range};
 
+use nalgebra::{Point3, Projective3, Vector2, Vector3};
+
+use super::triangulate;
+
+/// Names of the attributes loaders fill in & the voxelizer reads
+pub const POSITION: &str = "position";
+pub const NORMAL: &str = "normal";
+pub const UV: &str = "uv";
+pub const COLOR: &str = "color";
+
+/// Layout of the attributes interleaved in each vertex of a [`Mesh`]
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
 pub struct VertexDescriptor {
     length: usize,
-    fields: HashMap<String, Range<usize>>
+    fields: HashMap<String, Range<usize>>,
 }
 
-pub struct Mesh<'desc, Data, Index = usize> {
-    vert_data: Vec<Data>,
-    vert_descriptor: &'desc VertexDescriptor,
-    face_indices: Vec<Index>,
-    face_arities: Vec<Index>,
+impl VertexDescriptor {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Append an attribute made of `width` components
+    pub fn with(mut self, name: &str, width: usize) -> Self {
+        self.fields
+            .insert(name.to_owned(), self.length..self.length + width);
+        self.length += width;
+        self
+    }
+
+    /// Number of components in each vertex
+    pub fn len(&self) -> usize {
+        self.length
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.length == 0
+    }
+
+    pub fn field(&self, name: &str) -> Option<Range<usize>> {
+        self.fields.get(name).cloned()
+    }
+}
+
+/// Integer type faces use to refer to vertices
+pub trait MeshIndex: Copy + Debug + Eq + Hash {
+    fn from_usize(i: usize) -> Self;
+    fn to_usize(self) -> usize;
 }
 
-impl<D: Debug, I: Debug> Debug for Mesh<'_, D, I> {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        f.debug_struct("Mesh").field("vert_data", &self.vert_data).field("vert_descriptor", &self.vert_descriptor).field("face_indices", &self.face_indices).field("face_arities", &self.face_arities).finish()
+impl MeshIndex for u32 {
+    fn from_usize(i: usize) -> Self {
+        i as u32
+    }
+
+    fn to_usize(self) -> usize {
+        self as usize
     }
 }
 
-impl<D: Clone, I: Clone> Clone for Mesh<'_, D, I> {
-    fn clone(&self) -> Self {
-        Self {
-            vert_data: self.vert_data.clone(),
-            vert_descriptor: self.vert_descriptor,
-            face_indices: self.face_indices.clone(),
-            face_arities: self.face_arities.clone()
-        }
+impl MeshIndex for usize {
+    fn from_usize(i: usize) -> Self {
+        i
+    }
+
+    fn to_usize(self) -> usize {
+        self
     }
 }
 
-impl<'desc, D, I: Into<usize> + Copy> Mesh<'desc, D, I> {
-    pub fn new(descriptor: &'desc VertexDescriptor) -> Self {
+/// Format-agnostic polygon mesh. Every vertex holds the attributes laid out by its
+/// [`VertexDescriptor`], and faces are lists of vertex indices. Faces of one or two vertices are
+/// points & lines.
+#[derive(Debug, Clone)]
+pub struct Mesh<Data = f32, Index = u32> {
+    vert_data: Vec<Data>,
+    vert_descriptor: VertexDescriptor,
+    face_indices: Vec<Index>,
+    /// Number of vertices in each face; empty while every face is a triangle
+    face_arities: Vec<Index>,
+}
+
+impl<D: Copy, I: MeshIndex> Mesh<D, I> {
+    pub fn new(descriptor: VertexDescriptor) -> Self {
         Self {
-            vert_data: Default::default(),
+            vert_data: vec![],
             vert_descriptor: descriptor,
-            face_indices: Default::default(),
-            face_arities: Default::default()
+            face_indices: vec![],
+            face_arities: vec![],
+        }
+    }
+
+    pub fn descriptor(&self) -> &VertexDescriptor {
+        &self.vert_descriptor
+    }
+
+    pub fn has(&self, attribute: &str) -> bool {
+        self.vert_descriptor.fields.contains_key(attribute)
+    }
+
+    pub fn vertex_count(&self) -> usize {
+        self.vert_data.len() / self.vert_descriptor.length
+    }
+
+    pub fn vertex(&self, index: usize) -> &[D] {
+        let len = self.vert_descriptor.length;
+        &self.vert_data[index * len..(index + 1) * len]
+    }
+
+    pub fn vertex_mut(&mut self, index: usize) -> &mut [D] {
+        let len = self.vert_descriptor.length;
+        &mut self.vert_data[index * len..(index + 1) * len]
+    }
+
+    pub fn vertices(&self) -> impl Iterator<Item = &[D]> {
+        self.vert_data.chunks_exact(self.vert_descriptor.length)
+    }
+
+    pub fn vertices_mut(&mut self) -> impl Iterator<Item = &mut [D]> {
+        self.vert_data.chunks_exact_mut(self.vert_descriptor.length)
+    }
+
+    /// One attribute of a vertex, if the mesh has it
+    pub fn attribute(&self, index: usize, name: &str) -> Option<&[D]> {
+        let range = self.vert_descriptor.fields.get(name)?;
+        Some(&self.vertex(index)[range.clone()])
+    }
+
+    /// Add a vertex laid out like the descriptor, returning its index
+    pub fn push_vertex(&mut self, vertex: &[D]) -> I {
+        assert_eq!(vertex.len(), self.vert_descriptor.length);
+        let index = I::from_usize(self.vertex_count());
+        self.vert_data.extend_from_slice(vertex);
+        index
+    }
+
+    pub fn push_face(&mut self, face: &[I]) {
+        if face.len() != 3 && self.face_arities.is_empty() {
+            let triangles = self.face_indices.len() / 3;
+            self.face_arities = vec![I::from_usize(3); triangles];
         }
+        if !self.face_arities.is_empty() || face.len() != 3 {
+            self.face_arities.push(I::from_usize(face.len()));
+        }
+        self.face_indices.extend_from_slice(face);
+    }
+
+    pub fn has_faces(&self) -> bool {
+        !self.face_indices.is_empty()
     }
-    pub fn vertex(&self, index: usize) -> &[D] { &self.vert_data[index..index+self.vert_descriptor.length] }
-    pub fn vertex_mut(&mut self, index: usize) -> &mut [D] { &mut self.vert_data[index..index+self.vert_descriptor.length] }
-    pub fn vertices(&self) -> impl Iterator<Item = &[D]> { self.vert_data.chunks_exact(self.vert_descriptor.length) }
-    pub fn vertices_mut(&mut self) -> impl Iterator<Item = &mut [D]> { self.vert_data.chunks_exact_mut(self.vert_descriptor.length) }
-    pub fn face_indices(&self) -> Box<dyn Iterator<Item = &[I]>> where I: Into<usize> + Copy {
+
+    pub fn clear_faces(&mut self) {
+        self.face_indices.clear();
+        self.face_arities.clear();
+    }
+
+    pub fn face_indices(&self) -> Box<dyn Iterator<Item = &[I]> + '_> {
         match self.face_arities.len() {
             0 => Box::new(self.face_indices.chunks_exact(3)),
             _ => Box::new(self.face_arities.iter().scan(0usize, |i, a| {
                 let start = *i;
-                *i = *i + (*a).into();
+                *i += a.to_usize();
                 Some(&self.face_indices[start..*i])
-            }))
+            })),
         }
     }
-    pub fn faces<'h>(&'h self) -> impl Iterator<Item = Polygon<'h, D, I>> where I: Into<usize> + Copy {
-        self.face_indices().map(|face| Polygon { mesh: self, verts: face.iter().map(|fi| self.vertex((*fi).into())).collect() })
-    }
-    pub fn triangles<'h>(&'h self) -> impl Iterator<Item = Triangle<'h, D, I>> where I: Into<usize> + Copy {
-        self.face_indices().flat_map(|face| {
-            match face {
-                [a] => [],
-                [a, b] => [],
-                [a, b, c] => [Triangle::new(self, self.vertex((*a).into()), self.vertex((*b).into()), self.vertex((*c).into()))],
-                [a, b, c, d] => [Triangle::new(self, self.vertex((*a).into()), self.vertex((*b).into()), self.vertex((*c).into()))],
-                _ => todo!()
-            }
+
+    pub fn faces(&self) -> impl Iterator<Item = Polygon<'_, D, I>> {
+        self.face_indices().map(|indices| Polygon {
+            mesh: self,
+            indices,
         })
     }
 }
 
-pub struct Polygon<'mesh, Data, MIdx> {
-    mesh: &'mesh Mesh<'mesh, Data, MIdx>,
-    verts: Vec<&'mesh [Data]>,
+impl<I: MeshIndex> Mesh<f32, I> {
+    pub fn position(&self, index: usize) -> Vector3<f32> {
+        Vector3::from_column_slice(
+            self.attribute(index, POSITION)
+                .expect("meshes always have positions"),
+        )
+    }
+
+    pub fn set_position(&mut self, index: usize, position: Vector3<f32>) {
+        let range = self.vert_descriptor.fields[POSITION].clone();
+        self.vertex_mut(index)[range].copy_from_slice(position.as_slice());
+    }
+
+    pub fn positions(&self) -> impl Iterator<Item = Vector3<f32>> + '_ {
+        (0..self.vertex_count()).map(|i| self.position(i))
+    }
+
+    pub fn normal(&self, index: usize) -> Option<Vector3<f32>> {
+        self.attribute(index, NORMAL)
+            .map(Vector3::from_column_slice)
+    }
+
+    pub fn uv(&self, index: usize) -> Option<Vector2<f32>> {
+        self.attribute(index, UV).map(Vector2::from_column_slice)
+    }
+
+    pub fn color(&self, index: usize) -> Option<Vector3<f32>> {
+        self.attribute(index, COLOR).map(Vector3::from_column_slice)
+    }
+
+    /// Faces split into triangles by ear clipping, skipping points & lines
+    pub fn triangles(&self) -> impl Iterator<Item = Triangle<'_, f32, I>> {
+        self.faces().flat_map(|face| face.triangles())
+    }
+
+    /// Faces of one or two vertices, which are points & lines
+    pub fn lines(&self) -> impl Iterator<Item = Polygon<'_, f32, I>> {
+        self.faces().filter(|face| (1..3).contains(&face.len()))
+    }
+
+    /// Transform positions as points & normals as directions, keeping normals unit length
+    pub fn transform(&mut self, transform: &Projective3<f32>) {
+        let position = self.vert_descriptor.fields[POSITION].clone();
+        let normal = self.vert_descriptor.fields.get(NORMAL).cloned();
+        let normal_matrix = transform
+            .matrix()
+            .fixed_slice::<3, 3>(0, 0)
+            .try_inverse()
+            .map(|m| m.transpose());
+        for vertex in self.vertices_mut() {
+            let p = transform.transform_point(&Point3::from_slice(&vertex[position.clone()]));
+            vertex[position.clone()].copy_from_slice(p.coords.as_slice());
+            if let (Some(range), Some(matrix)) = (&normal, &normal_matrix) {
+                let n = matrix * Vector3::from_column_slice(&vertex[range.clone()]);
+                let n = n.try_normalize(f32::EPSILON).unwrap_or(n);
+                vertex[range.clone()].copy_from_slice(n.as_slice());
+            }
+        }
+    }
+}
+
+pub struct Polygon<'mesh, Data, Index> {
+    mesh: &'mesh Mesh<Data, Index>,
+    indices: &'mesh [Index],
+}
+
+impl<'mesh, D: Copy, I: MeshIndex> Polygon<'mesh, D, I> {
+    pub fn len(&self) -> usize {
+        self.indices.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.indices.is_empty()
+    }
+
+    pub fn indices(&self) -> &'mesh [I] {
+        self.indices
+    }
+
+    pub fn vertex(&self, corner: usize) -> &'mesh [D] {
+        self.mesh.vertex(self.indices[corner].to_usize())
+    }
+
+    pub fn vertices(&self) -> impl Iterator<Item = &'mesh [D]> + '_ {
+        (0..self.len()).map(|c| self.vertex(c))
+    }
 }
 
-impl<'mesh, Data, MIdx> Index<usize> for Polygon<'mesh, Data, MIdx> {
-    type Output = [Data];
-    fn index(&self, index: usize) -> &'mesh Self::Output {
-        self.vertex(index)
+impl<'mesh, I: MeshIndex> Polygon<'mesh, f32, I> {
+    pub fn position(&self, corner: usize) -> Vector3<f32> {
+        self.mesh.position(self.indices[corner].to_usize())
+    }
+
+    pub fn uv(&self, corner: usize) -> Option<Vector2<f32>> {
+        self.mesh.uv(self.indices[corner].to_usize())
+    }
+
+    /// Split into triangles by ear clipping; points & lines have none
+    pub fn triangles(&self) -> Vec<Triangle<'mesh, f32, I>> {
+        let points = (0..self.len())
+            .map(|c| self.position(c))
+            .collect::<Vec<_>>();
+        triangulate(&points)
+            .into_iter()
+            .map(|corners| Triangle {
+                mesh: self.mesh,
+                indices: corners.map(|c| self.indices[c]),
+            })
+            .collect()
     }
 }
 
-impl<'mesh, Data, MIdx> Polygon<'mesh, Data, MIdx> {
-    pub fn vertices(&self) -> &[&'mesh [Data]] { &self.verts }
-    pub fn vertex(&self, index: usize) -> &'mesh [Data] { self.verts[index] }
+pub struct Triangle<'mesh, Data, Index> {
+    mesh: &'mesh Mesh<Data, Index>,
+    indices: [Index; 3],
 }
 
-pub struct Triangle<'mesh, Data, MIdx> {
-    mesh: &'mesh Mesh<'mesh, Data, MIdx>,
-    verts: [&'mesh [Data]; 3]
+impl<'mesh, D: Copy, I: MeshIndex> Triangle<'mesh, D, I> {
+    pub fn indices(&self) -> [I; 3] {
+        self.indices
+    }
+
+    pub fn vertex(&self, corner: usize) -> &'mesh [D] {
+        self.mesh.vertex(self.indices[corner].to_usize())
+    }
 }
 
-impl<'mesh, Data, MIdx> Triangle<'mesh, Data, MIdx> {
-    fn new(mesh: &'mesh Mesh<'mesh, Data, MIdx>, a: &'mesh [Data], b: &'mesh [Data], c: &'mesh [Data]) -> Self {
-        Self {
-            mesh,
-            verts: [a, b, c]
-        }
+impl<'mesh, I: MeshIndex> Triangle<'mesh, f32, I> {
+    pub fn positions(&self) -> [Vector3<f32>; 3] {
+        self.indices.map(|i| self.mesh.position(i.to_usize()))
     }
 
-    fn from_quad_indices(mesh: &'mesh Mesh<'mesh, Data, MIdx>, [a, b, c, d]: &[MIdx]) -> (Self, Self) where MIdx: Into<usize> + Copy {
-        (Self)
+    pub fn uvs(&self) -> Option<[Vector2<f32>; 3]> {
+        let [a, b, c] = self.indices.map(|i| self.mesh.uv(i.to_usize()));
+        Some([a?, b?, c?])
     }
 }
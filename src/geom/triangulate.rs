@@ -0,0 +1,185 @@
+use nalgebra::{Vector2, Vector3};
+
+/// Split a planar polygon into triangles by ear clipping, returning indices into `points`.
+/// The polygon is projected onto its best-fit plane first, so slightly warped faces & concave
+/// faces are handled, and every triangle keeps the polygon's winding.
+pub fn triangulate(points: &[Vector3<f32>]) -> Vec<[usize; 3]> {
+    let n = points.len();
+    if n < 3 {
+        return vec![];
+    }
+    if n == 3 {
+        return vec![[0, 1, 2]];
+    }
+
+    // Newell's method gives the normal of the best-fit plane, facing the way the polygon winds
+    let mut normal = Vector3::zeros();
+    for (i, p) in points.iter().enumerate() {
+        let q = points[(i + 1) % n];
+        normal += Vector3::new(
+            (p.y - q.y) * (p.z + q.z),
+            (p.z - q.z) * (p.x + q.x),
+            (p.x - q.x) * (p.y + q.y),
+        );
+    }
+    let normal = match normal.try_normalize(f32::EPSILON) {
+        Some(normal) => normal,
+        None => return fan(n),
+    };
+    // Any basis with u × v = normal keeps the projected polygon counter-clockwise
+    let u = match normal.x.abs() < 0.9 {
+        true => Vector3::x(),
+        false => Vector3::y(),
+    };
+    let u = (u - normal * normal.dot(&u)).normalize();
+    let v = normal.cross(&u);
+    let projected = points
+        .iter()
+        .map(|p| Vector2::new(p.dot(&u), p.dot(&v)))
+        .collect::<Vec<_>>();
+
+    let mut remaining = (0..n).collect::<Vec<_>>();
+    let mut triangles = Vec::with_capacity(n - 2);
+    let mut i = 0;
+    // Vertices checked since the last ear was clipped
+    let mut checked = 0;
+    while remaining.len() > 3 {
+        let len = remaining.len();
+        let (prev, cur, next) = (
+            remaining[(i + len - 1) % len],
+            remaining[i % len],
+            remaining[(i + 1) % len],
+        );
+        let (a, b, c) = (projected[prev], projected[cur], projected[next]);
+        let convex = cross(b - a, c - b) > 0.;
+        let ear = convex
+            && !remaining
+                .iter()
+                .filter(|r| ![prev, cur, next].contains(r))
+                .any(|r| in_triangle(projected[*r], a, b, c));
+        // A self-intersecting or degenerate polygon may have no ears left; clipping anyway
+        // still covers it & always finishes
+        if ear || checked >= len {
+            triangles.push([prev, cur, next]);
+            remaining.remove(i % len);
+            checked = 0;
+        } else {
+            i += 1;
+            checked += 1;
+        }
+        i %= remaining.len();
+    }
+    triangles.push([remaining[0], remaining[1], remaining[2]]);
+    triangles
+}
+
+/// Fan triangulation from the first vertex, for polygons without a usable plane
+pub fn fan(n: usize) -> Vec<[usize; 3]> {
+    (2..n.max(2)).map(|i| [0, i - 1, i]).collect()
+}
+
+fn cross(a: Vector2<f32>, b: Vector2<f32>) -> f32 {
+    a.x * b.y - a.y * b.x
+}
+
+/// Whether `p` is inside or on the edge of the counter-clockwise triangle `abc`
+fn in_triangle(p: Vector2<f32>, a: Vector2<f32>, b: Vector2<f32>, c: Vector2<f32>) -> bool {
+    cross(b - a, p - a) >= 0. && cross(c - b, p - b) >= 0. && cross(a - c, p - c) >= 0.
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Twice the vector area of a polygon, facing the way it winds
+    fn area_normal(points: &[Vector3<f32>]) -> Vector3<f32> {
+        (0..points.len())
+            .map(|i| points[i].cross(&points[(i + 1) % points.len()]))
+            .sum()
+    }
+
+    fn check(points: &[Vector3<f32>]) -> Vec<[usize; 3]> {
+        let triangles = triangulate(points);
+        assert_eq!(triangles.len(), points.len() - 2);
+        let normal = area_normal(points);
+        let mut area = 0.;
+        for [a, b, c] in &triangles {
+            let (a, b, c) = (points[*a], points[*b], points[*c]);
+            let cross = (b - a).cross(&(c - a));
+            assert!(
+                cross.dot(&normal) > 0.,
+                "triangle winds against the polygon"
+            );
+            area += cross.norm();
+        }
+        assert!(
+            (area - normal.norm()).abs() < 1e-4,
+            "{area} != {}",
+            normal.norm()
+        );
+        triangles
+    }
+
+    /// Even-odd point in polygon test on the xy plane
+    fn inside(p: Vector2<f32>, points: &[Vector3<f32>]) -> bool {
+        let mut res = false;
+        for i in 0..points.len() {
+            let (a, b) = (points[i].xy(), points[(i + 1) % points.len()].xy());
+            if (a.y > p.y) != (b.y > p.y) && p.x < a.x + (p.y - a.y) / (b.y - a.y) * (b.x - a.x) {
+                res = !res;
+            }
+        }
+        res
+    }
+
+    #[test]
+    fn concave_l_shape() {
+        let points = [
+            Vector3::new(0., 0., 0.),
+            Vector3::new(2., 0., 0.),
+            Vector3::new(2., 1., 0.),
+            Vector3::new(1., 1., 0.),
+            Vector3::new(1., 2., 0.),
+            Vector3::new(0., 2., 0.),
+        ];
+        for [a, b, c] in check(&points) {
+            let centroid = (points[a] + points[b] + points[c]).xy() / 3.;
+            assert!(inside(centroid, &points), "triangle {a} {b} {c} is outside");
+        }
+    }
+
+    #[test]
+    fn concave_arrow_clockwise() {
+        // arrowhead with its notch at the origin, wound clockwise seen from +z
+        let points = [
+            Vector3::new(0., 0., 0.),
+            Vector3::new(-2., -2., 0.),
+            Vector3::new(0., 3., 0.),
+            Vector3::new(2., -2., 0.),
+        ];
+        let triangles = check(&points);
+        // the reflex vertex can't be clipped as an ear, so both triangles use it
+        assert!(triangles.iter().all(|t| t.contains(&0)));
+        for [a, b, c] in triangles {
+            let centroid = (points[a] + points[b] + points[c]).xy() / 3.;
+            assert!(inside(centroid, &points));
+        }
+    }
+
+    #[test]
+    fn concave_polygon_off_axis() {
+        // L shape on a tilted plane
+        let points = [(0., 0.), (3., 0.), (3., 1.), (1., 1.), (1., 3.), (0., 3.)]
+            .map(|(u, v)| Vector3::new(u, v * 0.6, v * 0.8 + u * 0.1));
+        check(&points);
+        let reversed = points.into_iter().rev().collect::<Vec<_>>();
+        check(&reversed);
+    }
+
+    #[test]
+    fn fan_from_first_vertex() {
+        assert_eq!(fan(5), vec![[0, 1, 2], [0, 2, 3], [0, 3, 4]]);
+        assert!(fan(2).is_empty());
+        assert!(fan(0).is_empty());
+    }
+}
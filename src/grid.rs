@@ -0,0 +1,277 @@
+//! Sparse voxel grid made of fixed-size chunks, for scenes where an octree would need a very deep
+//! root to cover a long, thin model
+
+use nalgebra::Vector3;
+use std::collections::{BTreeSet, HashMap};
+
+/// log₂ of the chunk edge length
+const CHUNK_BITS: u32 = 4;
+const CHUNK_SIZE: isize = 1 << CHUNK_BITS;
+const CHUNK_VOLUME: usize = 1 << (3 * CHUNK_BITS);
+
+type ChunkKey = (isize, isize, isize);
+
+/// 16³ voxels, stored as an occupancy bitmask and the values of occupied voxels in index order
+#[derive(Debug, Clone)]
+struct Chunk<T> {
+    occupied: [u64; CHUNK_VOLUME / 64],
+    values: Vec<T>,
+}
+
+impl<T> Chunk<T> {
+    fn new() -> Self {
+        Self {
+            occupied: [0; CHUNK_VOLUME / 64],
+            values: vec![],
+        }
+    }
+
+    fn contains(&self, index: usize) -> bool {
+        self.occupied[index / 64] & (1 << (index % 64)) != 0
+    }
+
+    /// Position in `values` of the voxel at `index`, or where it would be inserted
+    fn rank(&self, index: usize) -> usize {
+        let word = index / 64;
+        self.occupied[..word]
+            .iter()
+            .map(|w| w.count_ones() as usize)
+            .sum::<usize>()
+            + (self.occupied[word] & ((1 << (index % 64)) - 1)).count_ones() as usize
+    }
+
+    /// Indices of occupied voxels, in the same order as `values`
+    fn indices(&self) -> impl Iterator<Item = usize> + '_ {
+        self.occupied.iter().enumerate().flat_map(|(w, word)| {
+            (0..64)
+                .filter(move |b| word & (1 << b) != 0)
+                .map(move |b| w * 64 + b)
+        })
+    }
+}
+
+/// Sparse voxel store keeping 16³ chunks in a hash map, so memory only grows with the number of
+/// chunks touched. Voxels are visited chunk by chunk, in x, y, then z order.
+#[derive(Debug, Clone)]
+pub struct SparseGrid<T> {
+    chunks: HashMap<ChunkKey, Chunk<T>>,
+    /// Keys of non-empty chunks, for visiting them in a stable order
+    order: BTreeSet<ChunkKey>,
+    len: usize,
+}
+
+impl<T> Default for SparseGrid<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T> SparseGrid<T> {
+    pub fn new() -> Self {
+        Self {
+            chunks: HashMap::new(),
+            order: BTreeSet::new(),
+            len: 0,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Bytes allocated for chunks & values, not counting hash map overhead
+    pub fn memory_usage(&self) -> usize {
+        self.chunks
+            .values()
+            .map(|c| {
+                std::mem::size_of::<Chunk<T>>() + c.values.capacity() * std::mem::size_of::<T>()
+            })
+            .sum()
+    }
+
+    pub fn get(&self, pos: Vector3<isize>) -> Option<&T> {
+        let (key, index) = locate(pos);
+        let chunk = self.chunks.get(&key)?;
+        chunk
+            .contains(index)
+            .then(|| &chunk.values[chunk.rank(index)])
+    }
+
+    pub fn get_mut(&mut self, pos: Vector3<isize>) -> Option<&mut T> {
+        let (key, index) = locate(pos);
+        let chunk = self.chunks.get_mut(&key)?;
+        match chunk.contains(index) {
+            true => {
+                let rank = chunk.rank(index);
+                Some(&mut chunk.values[rank])
+            }
+            false => None,
+        }
+    }
+
+    /// Insert a voxel, returning the value it replaced
+    pub fn insert(&mut self, pos: Vector3<isize>, value: T) -> Option<T> {
+        let (key, index) = locate(pos);
+        let chunk = self.chunks.entry(key).or_insert_with(|| {
+            self.order.insert(key);
+            Chunk::new()
+        });
+        let rank = chunk.rank(index);
+        if chunk.contains(index) {
+            return Some(std::mem::replace(&mut chunk.values[rank], value));
+        }
+        chunk.occupied[index / 64] |= 1 << (index % 64);
+        chunk.values.insert(rank, value);
+        self.len += 1;
+        None
+    }
+
+    /// Remove a voxel, dropping its chunk if it was the last one in it
+    pub fn remove(&mut self, pos: Vector3<isize>) -> Option<T> {
+        let (key, index) = locate(pos);
+        let chunk = self.chunks.get_mut(&key)?;
+        if !chunk.contains(index) {
+            return None;
+        }
+        chunk.occupied[index / 64] &= !(1 << (index % 64));
+        let value = chunk.values.remove(chunk.rank(index));
+        if chunk.values.is_empty() {
+            self.chunks.remove(&key);
+            self.order.remove(&key);
+        }
+        self.len -= 1;
+        Some(value)
+    }
+
+    pub fn first(&self) -> Option<(Vector3<isize>, &T)> {
+        let key = self.order.iter().next()?;
+        let chunk = &self.chunks[key];
+        let index = chunk.indices().next()?;
+        Some((position(*key, index), &chunk.values[0]))
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (Vector3<isize>, &T)> + '_ {
+        self.order.iter().flat_map(|key| {
+            let chunk = &self.chunks[key];
+            chunk
+                .indices()
+                .zip(&chunk.values)
+                .map(|(index, value)| (position(*key, index), value))
+        })
+    }
+
+    /// Remove every voxel, in iteration order
+    pub fn drain(&mut self) -> impl Iterator<Item = (Vector3<isize>, T)> {
+        let mut grid = std::mem::take(self);
+        std::mem::take(&mut grid.order)
+            .into_iter()
+            .flat_map(move |key| {
+                let chunk = grid.chunks.remove(&key).unwrap();
+                let indices = chunk.indices().collect::<Vec<_>>();
+                indices
+                    .into_iter()
+                    .zip(chunk.values)
+                    .map(move |(index, value)| (position(key, index), value))
+            })
+    }
+}
+
+/// Chunk key & index within the chunk of a voxel
+fn locate(pos: Vector3<isize>) -> (ChunkKey, usize) {
+    let key = (
+        pos.x.div_euclid(CHUNK_SIZE),
+        pos.y.div_euclid(CHUNK_SIZE),
+        pos.z.div_euclid(CHUNK_SIZE),
+    );
+    let local = pos.map(|c| c.rem_euclid(CHUNK_SIZE) as usize);
+    (
+        key,
+        local.x << (2 * CHUNK_BITS) | local.y << CHUNK_BITS | local.z,
+    )
+}
+
+fn position(key: ChunkKey, index: usize) -> Vector3<isize> {
+    let mask = CHUNK_SIZE as usize - 1;
+    Vector3::new(
+        key.0 * CHUNK_SIZE + (index >> (2 * CHUNK_BITS) & mask) as isize,
+        key.1 * CHUNK_SIZE + (index >> CHUNK_BITS & mask) as isize,
+        key.2 * CHUNK_SIZE + (index & mask) as isize,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn locate_round_trips() {
+        for pos in [
+            Vector3::new(0, 0, 0),
+            Vector3::new(15, 16, 17),
+            Vector3::new(-1, -16, -17),
+            Vector3::new(-33, 40, 2),
+        ] {
+            let (key, index) = locate(pos);
+            assert!(index < CHUNK_VOLUME);
+            assert_eq!(position(key, index), pos);
+        }
+        assert_eq!(locate(Vector3::new(-1, 0, 0)), ((-1, 0, 0), 15 << 8));
+    }
+
+    #[test]
+    fn insert_get_remove() {
+        let mut grid = SparseGrid::new();
+        assert_eq!(grid.insert(Vector3::new(1, 2, 3), 'a'), None);
+        assert_eq!(grid.insert(Vector3::new(-1, 2, 3), 'b'), None);
+        assert_eq!(grid.insert(Vector3::new(1, 2, 3), 'c'), Some('a'));
+        assert_eq!(grid.len(), 2);
+        assert_eq!(grid.get(Vector3::new(1, 2, 3)), Some(&'c'));
+        assert_eq!(grid.get(Vector3::new(1, 2, 4)), None);
+        assert_eq!(grid.remove(Vector3::new(-1, 2, 3)), Some('b'));
+        assert_eq!(grid.remove(Vector3::new(-1, 2, 3)), None);
+        assert_eq!(grid.len(), 1);
+        assert_eq!(grid.iter().count(), 1);
+    }
+
+    #[test]
+    fn iterates_chunk_by_chunk_in_xyz_order() {
+        let positions = [
+            Vector3::new(0, 0, 0),
+            Vector3::new(0, 0, 1),
+            Vector3::new(0, 1, 0),
+            Vector3::new(1, 0, 0),
+            Vector3::new(15, 15, 15),
+            Vector3::new(-1, 0, 0),
+            Vector3::new(-16, 5, 5),
+            Vector3::new(0, -1, 20),
+            Vector3::new(0, 16, -3),
+            Vector3::new(16, 0, 0),
+        ];
+        let mut grid = SparseGrid::new();
+        for (i, pos) in positions.iter().enumerate().rev() {
+            grid.insert(*pos, i);
+        }
+
+        // chunks sorted by key, then voxels by x, y & z within each chunk, z changing fastest
+        let mut expected = positions.to_vec();
+        expected.sort_by_key(|pos| {
+            let (key, _) = locate(*pos);
+            (key, pos.x, pos.y, pos.z)
+        });
+        let order = grid.iter().map(|(pos, _)| pos).collect::<Vec<_>>();
+        assert_eq!(order, expected);
+        assert_eq!(grid.first().map(|(pos, _)| pos), Some(expected[0]));
+        for (pos, i) in grid.iter() {
+            assert_eq!(pos, positions[*i]);
+        }
+
+        let drained = grid.drain().map(|(pos, _)| pos).collect::<Vec<_>>();
+        assert_eq!(drained, expected);
+        assert!(grid.is_empty());
+        assert!(grid.first().is_none());
+    }
+}
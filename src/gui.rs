@@ -201,6 +201,27 @@ impl Gui {
         );
         ui.end_row();
 
+        ui.label("Color Tolerance").on_hover_text(
+            "How different merged colors may be from the average color of their brick; lower values keep more detail",
+        );
+        match &mut self.conv_opts {
+            ConversionOptions::Simplify {
+                lossless: false,
+                color_tolerance,
+                ..
+            } => {
+                let mut tolerance = color_tolerance.unwrap_or(1.);
+                let slider = Slider::new(&mut tolerance, RangeInclusive::new(0., 1.));
+                if ui.add(slider).changed() {
+                    *color_tolerance = Some(tolerance);
+                }
+            }
+            _ => {
+                ui.add_enabled(false, Slider::new(&mut 1f32, RangeInclusive::new(0., 1.)));
+            }
+        }
+        ui.end_row();
+
         ui.label("Raise Underground")
             .on_hover_text("Prevents bricks under the ground plate in Brickadia");
         ui.add(Checkbox::new(&mut self.raise, ""));
@@ -209,21 +230,56 @@ impl Gui {
         ui.label("Match to Colorset").on_hover_text(
             "Modify the color of the model to match the default color palette in Brickadia",
         );
-        ui.add_enabled(
-            !self.rampify,
-            Checkbox::new(&mut self.match_brickadia_colorset, "Use Default Palette"),
-        );
+        ui.add(Checkbox::new(
+            &mut self.match_brickadia_colorset,
+            "Use Default Palette",
+        ));
         ui.end_row();
 
-        ui.label("Rampify").on_hover_text(
-            "Creates a Lego-World like rampification of the model, uses default colorset",
-        );
+        ui.label("Rampify")
+            .on_hover_text("Creates a Lego-World like rampification of the model");
         ui.add(Checkbox::new(
             &mut self.rampify,
             "Run the result through Wrapperup's plate-rampifier",
         ));
         ui.end_row();
 
+        if let ConversionOptions::Rampify { ramps, .. } = &mut self.conv_opts {
+            ui.label("Ramps")
+                .on_hover_text("Which slopes to place ramps on, and whether to keep the wedges placed alongside them");
+            ui.horizontal(|ui| {
+                ui.add(Checkbox::new(&mut ramps.floor_ramps, "Floors"));
+                ui.add(Checkbox::new(&mut ramps.ceiling_ramps, "Ceilings"));
+                ui.add(Checkbox::new(&mut ramps.wedges, "Wedges"));
+            });
+            ui.end_row();
+
+            ui.label("Ramp Length").on_hover_text(
+                "Shortest & longest runs, in voxels along the slope, of the ramps kept; others are replaced with bricks",
+            );
+            ui.horizontal(|ui| {
+                ui.add(DragValue::new(&mut ramps.min_length).clamp_range(1..=500));
+                let mut limited = ramps.max_length.is_some();
+                ui.add(Checkbox::new(&mut limited, "at most"));
+                let mut max_length = ramps.max_length.unwrap_or(ramps.min_length.max(8));
+                ui.add_enabled(
+                    limited,
+                    DragValue::new(&mut max_length).clamp_range(ramps.min_length..=500),
+                );
+                ramps.max_length = limited.then(|| max_length);
+            });
+            ui.end_row();
+
+            ui.label("Max Fill Size").on_hover_text(
+                "Largest size, in plates along each axis, of the bricks that fill the space left by ramps",
+            );
+            ui.add(Slider::new(
+                &mut ramps.max_fill,
+                RangeInclusive::new(1, 500),
+            ));
+            ui.end_row();
+        }
+
         ui.label("Scale")
             .on_hover_text("Adjusts the overall size of the generated save");
         ui.add(
@@ -236,7 +292,7 @@ impl Gui {
 
         ui.label("Bricktype")
             .on_hover_text("Which type of bricks will make up the generated save, use default to get a stud texture");
-        ui.add_enabled_ui(!self.rampify, |ui| {
+        ui.horizontal(|ui| {
             ComboBox::from_label("")
                 .selected_text(format!("{:?}", &mut self.bricktype))
                 .show_ui(ui, |ui| {
@@ -1,4 +1,4 @@
-use nalgebra::Vector3;
+use nalgebra::{Vector2, Vector3};
 
 fn find_min_max(f0: f32, f1: f32, f2: f32, min: &mut f32, max: &mut f32) {
     *min = f0;
@@ -265,3 +265,173 @@ pub fn intersect(
     // Orthogonal projection to determine UV coordinates
     Some((v0.dot(&normal) * normal) / normal.dot(&normal) + center)
 }
+
+/// Thin, 6-separating triangle/voxel test after Schwarz & Seidel. The triangle's plane has to
+/// cross the voxel within half a voxel of its center along the plane's dominant axis, and the
+/// triangle has to overlap the diamond inscribed in the voxel in each axis-aligned projection.
+/// Planes exactly halfway between two voxels only claim the one on their front side, so
+/// surfaces stay one voxel thick.
+pub fn intersect_thin(
+    half_box: f32,
+    center: Vector3<f32>,
+    p0: Vector3<f32>,
+    p1: Vector3<f32>,
+    p2: Vector3<f32>,
+) -> bool {
+    let v = [p0 - center, p1 - center, p2 - center];
+    let normal = (v[1] - v[0]).cross(&(v[2] - v[0]));
+    let thickness = half_box * normal.amax();
+    if thickness == 0. {
+        return false;
+    }
+    let distance = -normal.dot(&v[0]);
+    if distance <= -thickness || distance > thickness {
+        return false;
+    }
+
+    for axis in 0..3 {
+        if normal[axis] == 0. {
+            continue;
+        }
+        // project along `axis`, keeping the triangle counter-clockwise
+        let (a, b) = ((axis + 1) % 3, (axis + 2) % 3);
+        let sign = normal[axis].signum();
+        for i in 0..3 {
+            let (p, q) = (v[i], v[(i + 1) % 3]);
+            let edge_normal = Vector2::new(p[b] - q[b], q[a] - p[a]) * sign;
+            let reach = half_box * edge_normal.amax();
+            if reach - edge_normal.x * p[a] - edge_normal.y * p[b] < 0. {
+                return false;
+            }
+        }
+    }
+    true
+}
+
+/// Clips a triangle to an axis-aligned box, returning the vertices of the polygon where they
+/// overlap (empty if they don't)
+pub fn clip_triangle(
+    half_box: f32,
+    center: Vector3<f32>,
+    p0: Vector3<f32>,
+    p1: Vector3<f32>,
+    p2: Vector3<f32>,
+) -> Vec<Vector3<f32>> {
+    let mut polygon = vec![p0, p1, p2];
+
+    // Sutherland-Hodgman against each of the six faces of the box
+    for axis in 0..3 {
+        for sign in [-1f32, 1f32] {
+            if polygon.is_empty() {
+                return polygon;
+            }
+            let bound = center[axis] + sign * half_box;
+            // positive when inside the box
+            let dist = |p: &Vector3<f32>| sign * (bound - p[axis]);
+
+            let mut clipped = Vec::<Vector3<f32>>::with_capacity(polygon.len() + 1);
+            for (i, cur) in polygon.iter().enumerate() {
+                let prev = &polygon[(i + polygon.len() - 1) % polygon.len()];
+                let (d_cur, d_prev) = (dist(cur), dist(prev));
+                if d_cur >= 0. {
+                    if d_prev < 0. {
+                        clipped.push(prev + (cur - prev) * (d_prev / (d_prev - d_cur)));
+                    }
+                    clipped.push(*cur);
+                } else if d_prev >= 0. {
+                    clipped.push(prev + (cur - prev) * (d_prev / (d_prev - d_cur)));
+                }
+            }
+            polygon = clipped;
+        }
+    }
+
+    polygon
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Large triangle on the plane `axis` = `f(u, v)`, wound counter-clockwise around +`axis`
+    fn plane(axis: usize, f: impl Fn(f32, f32) -> f32) -> [Vector3<f32>; 3] {
+        [(-50., -50.), (100., -50.), (-50., 100.)].map(|(u, v)| {
+            let mut p = Vector3::zeros();
+            p[axis] = f(u, v);
+            p[(axis + 1) % 3] = u;
+            p[(axis + 2) % 3] = v;
+            p
+        })
+    }
+
+    fn hits(center: Vector3<f32>, [p0, p1, p2]: [Vector3<f32>; 3]) -> bool {
+        intersect_thin(0.5, center, p0, p1, p2)
+    }
+
+    /// Every column of voxels along `axis` should hold exactly one voxel of the surface
+    fn assert_one_per_column(axis: usize, triangle: [Vector3<f32>; 3]) {
+        for i in 0..6 {
+            for j in 0..6 {
+                let count = (-10..20)
+                    .filter(|k| {
+                        let mut center = Vector3::zeros();
+                        center[axis] = *k as f32 + 0.5;
+                        center[(axis + 1) % 3] = i as f32 + 0.5;
+                        center[(axis + 2) % 3] = j as f32 + 0.5;
+                        hits(center, triangle)
+                    })
+                    .count();
+                assert_eq!(count, 1, "column ({i}, {j}) along axis {axis}");
+            }
+        }
+    }
+
+    #[test]
+    fn plane_between_voxels_claims_front_side() {
+        let triangle = plane(2, |_, _| 1.);
+        assert!(!hits(Vector3::new(0.5, 0.5, 0.5), triangle));
+        assert!(hits(Vector3::new(0.5, 0.5, 1.5), triangle));
+
+        let [p0, p1, p2] = triangle;
+        let flipped = [p0, p2, p1];
+        assert!(hits(Vector3::new(0.5, 0.5, 0.5), flipped));
+        assert!(!hits(Vector3::new(0.5, 0.5, 1.5), flipped));
+    }
+
+    #[test]
+    fn axis_aligned_planes_are_one_voxel_thick() {
+        for axis in 0..3 {
+            assert_one_per_column(axis, plane(axis, |_, _| 2.3));
+            assert_one_per_column(axis, plane(axis, |_, _| 4.));
+        }
+    }
+
+    #[test]
+    fn slanted_planes_are_6_separating() {
+        for axis in 0..3 {
+            assert_one_per_column(axis, plane(axis, |u, v| 0.3 * u + 0.2 * v + 0.1));
+            assert_one_per_column(axis, plane(axis, |u, v| 1. - 0.9 * u + 0.45 * v));
+        }
+    }
+
+    #[test]
+    fn triangle_edges() {
+        let center = Vector3::new(0.5, 0.5, 0.5);
+        // small triangle inside the voxel
+        let inner = [
+            Vector3::new(0.4, 0.4, 0.5),
+            Vector3::new(0.6, 0.4, 0.5),
+            Vector3::new(0.5, 0.6, 0.5),
+        ];
+        assert!(hits(center, inner));
+        // same plane, but the triangle stops short of the voxel
+        let beside = inner.map(|p| p + Vector3::new(3., 0., 0.));
+        assert!(!hits(center, beside));
+        // far away
+        let far = inner.map(|p| p + Vector3::new(40., -12., 7.));
+        assert!(!hits(center, far));
+        // degenerate triangles have no plane to intersect
+        let line = [inner[0], inner[1], (inner[0] + inner[1]) / 2.];
+        assert!(!hits(center, line));
+    }
+}
@@ -7,10 +7,15 @@ use std::{
 
 use nalgebra::{Affine3, Point3, Projective3, Scale3, Transform3, Vector3};
 
+use crate::geom::Mesh;
 use crate::BrickType;
 
 mod obj_ext;
+mod points;
+mod validate;
 pub use obj_ext::*;
+pub use points::*;
+pub use validate::*;
 
 #[derive(Debug, thiserror::Error)]
 pub enum LoadError {
@@ -18,27 +23,50 @@ pub enum LoadError {
     Model(#[from] tobj::LoadError),
     #[error("Failed to load {0} texture file from {1:?}: {2}")]
     Image(String, PathBuf, image::ImageError),
+    #[error("Failed to read {0:?}: {1}")]
+    Io(PathBuf, std::io::Error),
+    #[error("Failed to parse {0:?} at line {1}: {2}")]
+    Points(PathBuf, usize, String),
 }
 
+/// Material properties used for coloring voxels. Colors & textures are multiplied together, the
+/// same way a renderer would display them.
 #[derive(Debug)]
-pub enum ObjMaterial {
-    Color(f32, f32, f32, f32),
-    ImageKey(String),
+pub struct ObjMaterial {
+    /// Diffuse color (Kd) & dissolve (d)
+    pub color: [f32; 4],
+    /// Image key of the diffuse texture (map_Kd)
+    pub texture: Option<String>,
+    /// Image key of the alpha texture (map_d); only its alpha channel is meaningful
+    pub alpha_texture: Option<String>,
+}
+
+/// A named mesh with one material, as loaders hand them to the voxelizer. Meshes without faces
+/// are point clouds.
+#[derive(Debug, Clone)]
+pub struct Model {
+    pub name: String,
+    pub mesh: Mesh,
+    /// Index into the model set's materials
+    pub material_id: Option<usize>,
 }
 
 #[derive(Debug, Default)]
 pub struct ModelSet {
-    pub models: Vec<tobj::Model>,
+    pub models: Vec<Model>,
     pub materials: Vec<ObjMaterial>,
 }
 
 impl ModelSet {
-    pub fn raw_points(&self) -> impl Iterator<Item = &[f32]> + '_ {
-        self.models.iter().flat_map(|m| m.mesh.raw_points())
+    pub fn positions(&self) -> impl Iterator<Item = Vector3<f32>> + '_ {
+        self.models.iter().flat_map(|m| m.mesh.positions())
     }
 
-    pub fn raw_points_mut(&mut self) -> impl Iterator<Item = &mut [f32]> + '_ {
-        self.models.iter_mut().flat_map(|m| m.mesh.raw_points_mut())
+    /// Transform every mesh in place
+    pub fn transform(&mut self, transform: &Projective3<f32>) {
+        for model in &mut self.models {
+            model.mesh.transform(transform);
+        }
     }
 }
 
@@ -61,38 +89,107 @@ impl ObjRegistry {
             Err(e) | Ok((_, Err(e))) => return Err(e.into()),
             Ok((models, Ok(materials))) => (models, materials),
         };
+        let elements = scan_obj_elements(path).map_err(|e| LoadError::Io(path.to_owned(), e))?;
+        let mut polylines = elements.polylines.into_iter();
         let mut mset = ModelSet {
-            models,
+            models: models
+                .into_iter()
+                .map(|model| Model {
+                    mesh: mesh_from_tobj(&model.mesh, &mut polylines),
+                    material_id: model.mesh.material_id,
+                    name: model.name,
+                })
+                .collect(),
             ..Default::default()
         };
+        mset.models
+            .extend(elements.points.into_iter().map(|points| {
+                Model {
+                    material_id: points
+                        .material
+                        .and_then(|name| materials.iter().position(|m| m.name == name)),
+                    mesh: points.mesh,
+                    name: points.name,
+                }
+            }));
         tracing::info!("Registering materials...");
         for material in materials {
-            if material.diffuse_texture.is_empty() {
-                mset.materials.push(ObjMaterial::Color(
-                    material.diffuse[0],
-                    material.diffuse[1],
-                    material.diffuse[2],
-                    material.dissolve,
-                ));
-            } else {
-                let image_path = path.parent().unwrap().join(&material.diffuse_texture);
-                let key = image_path.to_str().unwrap().to_owned();
-                if !self.images.contains_key(&key) {
-                    tracing::info!(
-                        "\tLoading diffuse texture for {} from: {:?}",
-                        material.name,
-                        image_path
-                    );
-                    self.images.insert(
-                        key.clone(),
-                        image::open(&image_path)
-                            .map_err(|e| LoadError::Image(material.diffuse_texture, image_path, e))?
-                            .into_rgba8(),
-                    );
+            let mut color = [
+                material.diffuse[0],
+                material.diffuse[1],
+                material.diffuse[2],
+                material.dissolve,
+            ];
+            let texture = match material.diffuse_texture.is_empty() {
+                true => None,
+                false => {
+                    // tobj defaults a missing Kd to black, which would hide the texture entirely
+                    if color[0..3] == [0., 0., 0.] {
+                        tracing::debug!("\tIgnoring black diffuse color of {}", material.name);
+                        color[0..3].copy_from_slice(&[1., 1., 1.]);
+                    }
+                    Some(self.load_image(path, &material.name, &material.diffuse_texture, false)?)
+                }
+            };
+            let alpha_texture = match material.dissolve_texture.is_empty() {
+                true => None,
+                false => {
+                    Some(self.load_image(path, &material.name, &material.dissolve_texture, true)?)
                 }
-                mset.materials.push(ObjMaterial::ImageKey(key));
-            }
+            };
+            mset.materials.push(ObjMaterial {
+                color,
+                texture,
+                alpha_texture,
+            });
         }
+        self.model_sets.push(mset);
         Ok(())
     }
+
+    /// Load an image referenced by an mtl file, returning its key in `self.images`.
+    ///
+    /// Alpha maps are converted so that their coverage is always stored in the alpha channel:
+    /// images without an alpha channel use their luminance instead.
+    fn load_image(
+        &mut self,
+        obj_path: &Path,
+        material_name: &str,
+        file_name: &str,
+        alpha_map: bool,
+    ) -> Result<String, LoadError> {
+        let image_path = obj_path.parent().unwrap().join(file_name);
+        let mut key = image_path.to_str().unwrap().to_owned();
+        if alpha_map {
+            key.push_str("#alpha");
+        }
+        if !self.images.contains_key(&key) {
+            tracing::info!(
+                "\tLoading {} texture for {} from: {:?}",
+                if alpha_map { "alpha" } else { "diffuse" },
+                material_name,
+                image_path
+            );
+            let image = image::open(&image_path)
+                .map_err(|e| LoadError::Image(file_name.to_owned(), image_path, e))?;
+            let image = match alpha_map {
+                false => image.into_rgba8(),
+                true if image.color().has_alpha() => {
+                    let mut image = image.into_rgba8();
+                    for p in image.pixels_mut() {
+                        p.0 = [255, 255, 255, p.0[3]];
+                    }
+                    image
+                }
+                true => {
+                    let luma = image.into_luma8();
+                    image::RgbaImage::from_fn(luma.width(), luma.height(), |x, y| {
+                        image::Rgba([255, 255, 255, luma.get_pixel(x, y).0[0]])
+                    })
+                }
+            };
+            self.images.insert(key.clone(), image);
+        }
+        Ok(key)
+    }
 }
@@ -1,259 +1,182 @@
 use std::{
-    convert::TryInto,
-    fmt::Debug,
-    marker::PhantomData,
-    ops::Range,
-    slice::{Chunks, ChunksExact, ChunksExactMut, ChunksMut},
+    collections::HashMap,
+    fs::File,
+    io::{self, BufRead, BufReader},
+    path::Path,
 };
 
-use enumflags2::BitFlags;
-use nalgebra::{
-    Const, Matrix, MatrixSlice, MatrixSlice3x1, MatrixSliceMut, MatrixSliceMut3x1, Point3,
-    Projective3, SliceStorage, SliceStorageMut,
-};
-use parry3d::{bounding_volume::AABB, math::Point as PPoint};
 use tobj::Mesh;
 
-#[derive(Default, Debug, Clone)]
-pub struct ObjFaceRef<'mesh> {
-    positions: Vec<&'mesh [f32; 3]>,
-    normals: Vec<&'mesh [f32; 3]>,
-    colors: Vec<&'mesh [f32; 3]>,
-    uvs: Vec<&'mesh [f32; 2]>,
-}
-
-impl<'mesh> ObjFaceRef<'mesh> {
-    fn new(mesh: &'mesh Mesh, verts: impl IntoIterator<Item = u32>) -> Self {
-        let mut positions = vec![];
-        let mut normals = vec![];
-        let mut colors = vec![];
-        let mut uvs = vec![];
-        for v in verts {
-            let v = v as usize;
+use crate::geom::{self, VertexDescriptor, COLOR, NORMAL, POSITION, UV};
 
-            let pi = mesh.indices[v] as usize;
-            let ci = mesh.vertex_color_indices.get(v).map_or(pi, |c| *c as usize);
-            let ni = mesh.normal_indices.get(v).map_or(pi, |n| *n as usize);
-            let ti = mesh.texcoord_indices.get(v).map_or(pi, |t| *t as usize);
-
-            positions.push(&mesh.positions[pi..pi + 3].try_into().unwrap());
-            if let Some(n) = mesh.normals.get(ni..ni + 3) {
-                normals.push(n.try_into().unwrap());
-            }
-            if let Some(c) = mesh.vertex_color.get(ci..ci + 3) {
-                colors.push(c.try_into().unwrap());
-            }
-            if let Some(t) = mesh.texcoords.get(ti..ti + 2) {
-                uvs.push(t.try_into().unwrap());
-            }
-        }
-        ObjFaceRef {
-            positions,
-            normals,
-            colors,
-            uvs,
-        }
+/// Convert to the internal mesh format, giving each distinct combination of position, normal &
+/// texture coordinates its own vertex. Corners referring to missing data get an out-of-range
+/// index, leaving their faces for validation to drop. `polylines` says whether each face was an
+/// `l` record, which is split into two-vertex lines.
+pub fn mesh_from_tobj(mesh: &Mesh, polylines: &mut impl Iterator<Item = bool>) -> geom::Mesh {
+    let mut descriptor = VertexDescriptor::new().with(POSITION, 3);
+    if !mesh.normals.is_empty() {
+        descriptor = descriptor.with(NORMAL, 3);
     }
-}
-
-#[enumflags2::bitflags]
-#[repr(u8)]
-#[derive(Debug, Copy, Clone, Eq, PartialEq)]
-enum VertFieldFlags {
-    Normal = 0b0001,
-    Color = 0b0010,
-    Uv = 0b0100,
-}
-
-#[derive(Debug, Default, Clone)]
-pub struct ObjFace {
-    vert_data: Vec<f32>,
-    vert_fields: BitFlags<VertFieldFlags>,
-    vert_len: usize,
-}
-
-impl ObjFace {
-    fn vertices(&self) -> impl Iterator<Item = &[f32]> {
-        self.vert_data.chunks_exact(self.vert_len)
+    if !mesh.texcoords.is_empty() {
+        descriptor = descriptor.with(UV, 2);
     }
-    fn vertices_mut(&mut self) -> impl Iterator<Item = &mut [f32]> {
-        self.vert_data.chunks_exact_mut(self.vert_len)
+    if !mesh.vertex_color.is_empty() {
+        descriptor = descriptor.with(COLOR, 3);
     }
-}
-
-impl<'mesh> From<ObjFaceRef<'mesh>> for ObjFace {
-    fn from(o: ObjFaceRef<'mesh>) -> Self {
-        use VertFieldFlags as Field;
-        let mut fields = BitFlags::<VertFieldFlags>::empty();
-        let vert_data = vec![];
-        for v in 0..o.positions.len() {
-            vert_data.extend_from_slice(o.positions[v]);
-            if let Some(n) = o.normals.get(v) {
-                vert_data.extend_from_slice(*n);
-                fields |= Field::Normal;
-            }
-            if let Some(c) = o.colors.get(v) {
-                vert_data.extend_from_slice(*c);
-                fields |= Field::Color;
+    let mut res = geom::Mesh::new(descriptor);
+
+    // texture & normal indices are left empty when they match the position indices
+    let index = |buffer: &[u32], corner: usize| match buffer.is_empty() {
+        true => mesh.indices[corner],
+        false => buffer.get(corner).copied().unwrap_or(u32::MAX),
+    };
+    let mut vertices = HashMap::<(u32, u32, u32), u32>::new();
+    let mut vertex = Vec::with_capacity(res.descriptor().len());
+    let mut corner_vertex = |res: &mut geom::Mesh, corner: usize| {
+        let p = mesh.indices[corner];
+        let key = (
+            p,
+            index(&mesh.texcoord_indices, corner),
+            index(&mesh.normal_indices, corner),
+        );
+        if let Some(v) = vertices.get(&key) {
+            return *v;
+        }
+        let (p, t, n) = (p as usize, key.1 as usize, key.2 as usize);
+        vertex.clear();
+        let fields = [
+            (&mesh.positions, 3 * p..3 * p + 3),
+            (&mesh.normals, 3 * n..3 * n + 3),
+            (&mesh.texcoords, 2 * t..2 * t + 2),
+            (&mesh.vertex_color, 3 * p..3 * p + 3),
+        ];
+        for (buffer, range) in fields {
+            if buffer.is_empty() {
+                continue;
             }
-            if let Some(t) = o.uvs.get(v) {
-                vert_data.extend_from_slice(*t);
-                fields |= Field::Uv;
+            match buffer.get(range) {
+                Some(data) => vertex.extend_from_slice(data),
+                None => return u32::MAX,
             }
         }
-        let bits = fields.bits();
-        Self {
-            vert_data,
-            vert_fields: fields,
-            vert_len: (3
-                + (((fields & Field::Normal).bits() >> 0) * 3)
-                + (((fields & Field::Color).bits() >> 1) * 3)
-                + (((fields & Field::Uv).bits() >> 2) * 2)) as usize,
+        let v = res.push_vertex(&vertex);
+        vertices.insert(key, v);
+        v
+    };
+
+    let mut face = vec![];
+    let mut start = 0;
+    let arities = match mesh.face_arities.is_empty() {
+        true => vec![3; mesh.indices.len() / 3],
+        false => mesh.face_arities.clone(),
+    };
+    for arity in arities {
+        let end = (start + arity as usize).min(mesh.indices.len());
+        face.clear();
+        face.extend((start..end).map(|c| corner_vertex(&mut res, c)));
+        match polylines.next() {
+            Some(true) if face.len() > 2 => face.windows(2).for_each(|line| res.push_face(line)),
+            _ => res.push_face(&face),
         }
+        start = end;
     }
+    res
 }
 
-// #[derive(Default, Debug)]
-// pub struct ObjFaceMut<'mesh> {
-//     vertices: Vec<ObjVertexMut<'mesh>>,
-//     _lifetime: PhantomData<&'mesh mut ()>,
-// }
-
-#[derive(Debug, Clone, Copy)]
-pub struct ObjVertexRef<'mesh> {
-    position: &'mesh [f32; 3],
-    normal: Option<&'mesh [f32; 3]>,
-    color: Option<&'mesh [f32; 3]>,
-    uv: Option<&'mesh [f32; 2]>,
+/// What tobj loses when loading an OBJ file: it reads `l` records like `f` records, so polylines
+/// would be filled in as polygons, and skips `p` records entirely
+#[derive(Debug, Default)]
+pub struct ObjElements {
+    /// Whether each `f` or `l` record, in the order tobj reads them, is an `l`
+    pub polylines: Vec<bool>,
+    /// Meshes of the `p` records in each object, made of one-vertex faces
+    pub points: Vec<ObjPoints>,
 }
 
-// #[derive(Debug)]
-// pub struct ObjVertexMut<'mesh> {
-//     position: &'mesh mut [f32; 3],
-//     color: Option<&'mesh mut [f32; 3]>,
-//     normal: Option<&'mesh mut [f32; 3]>,
-//     uv: Option<&'mesh mut [f32; 2]>,
-// }
-
-pub trait MeshExt {
-    fn raw_points(&self) -> ChunksExact<f32>;
-    fn raw_points_mut(&mut self) -> ChunksExactMut<f32>;
-
-    fn vertices(&self) -> Box<dyn Iterator<Item = Vec<f32>>>;
-
-    fn vertex_format(&self) -> BitFlags<VertFieldFlags>;
-
-    fn aabb(&self) -> AABB {
-        let mut res = AABB::new_invalid();
-        for [x, y, z] in self.raw_points() {
-            res.take_point(PPoint::new(*x, *y, *z));
-        }
-        res
-    }
-
-    fn points<'mesh>(&'mesh self) -> Box<dyn Iterator<Item = (&[f32; 3], Point3<f32>)>> {
-        Box::new(
-            self.raw_points()
-                .map(|p| (p.try_into().unwrap(), Point3::from_slice(p))),
-        )
-    }
-    fn points_mut<'mesh>(
-        &'mesh mut self,
-    ) -> Box<dyn Iterator<Item = (&'mesh mut [f32; 3], Point3<f32>)>> {
-        Box::new(
-            self.raw_points_mut()
-                .map(|p| (p.try_into().unwrap(), Point3::from_slice(p))),
-        )
-    }
-    fn faces<'mesh>(&'mesh self) -> Box<dyn Iterator<Item = ObjFaceRef<'mesh>> + '_>;
-    fn triangles<'mesh>(&'mesh self) -> Box<dyn Iterator<Item = [u32; 3]> + '_>;
+#[derive(Debug)]
+pub struct ObjPoints {
+    pub name: String,
+    /// Name of the material in use
+    pub material: Option<String>,
+    pub mesh: geom::Mesh,
 }
 
-impl MeshExt for tobj::Mesh {
-    fn raw_points(&self) -> ChunksExact<f32> {
-        self.positions.chunks_exact(3)
-    }
-
-    fn raw_points_mut(&mut self) -> ChunksExactMut<f32> {
-        self.positions.chunks_exact_mut(3)
-    }
-
-    fn vertex_format(&self) -> BitFlags<VertFieldFlags> {
-        use VertFieldFlags as Field;
-        unsafe {
-            BitFlags::<VertFieldFlags>::from_bits_unchecked(
-                0b0000
-                    | (Field::Normal as u8 * (!self.normals.is_empty() as u8))
-                    | (Field::Color as u8 * (!self.vertex_color.is_empty() as u8))
-                    | (Field::Uv as u8 * (!self.texcoords.is_empty() as u8)),
-            )
+/// Find the records of `path` that tobj doesn't keep apart. Positions are only read again when
+/// the file has `p` records.
+pub fn scan_obj_elements(path: &Path) -> io::Result<ObjElements> {
+    let mut elements = ObjElements::default();
+    let mut name = String::new();
+    let mut material = None;
+    let mut positions = 0usize;
+    // object, material & position indices of each run of points
+    let mut runs = Vec::<(String, Option<String>, Vec<usize>)>::new();
+    for line in BufReader::new(File::open(path)?).lines() {
+        let line = line?;
+        let mut words = line.split_whitespace();
+        match words.next() {
+            Some("v") => positions += 1,
+            Some("f") => elements.polylines.push(false),
+            Some("l") => elements.polylines.push(true),
+            Some("o") | Some("g") => name = words.collect::<Vec<_>>().join(" "),
+            Some("usemtl") => material = words.next().map(str::to_owned),
+            Some("p") => {
+                let indices = words.filter_map(|w| {
+                    // indices count from 1, or back from the last position if negative
+                    match w.split('/').next()?.parse::<isize>().ok()? {
+                        i if i > 0 => Some(i as usize - 1),
+                        i if i < 0 => positions.checked_sub(i.unsigned_abs()),
+                        _ => None,
+                    }
+                });
+                match runs.last_mut() {
+                    Some(run) if run.0 == name && run.1 == material => run.2.extend(indices),
+                    _ => runs.push((name.clone(), material.clone(), indices.collect())),
+                }
+            }
+            _ => {}
         }
     }
-
-    fn vertices(&self) -> Box<dyn Iterator<Item = Vec<f32>>> {
-        Box::new(self.raw_points().enumerate().map(|(v, [x, y, z])| {
-            let mut res = vec![*x, *y, *z];
-            let v3 = v * 3;
-            let v2 = v * 2;
-            if let Some(n) = self.normals.get(v3..v3 + 3) {
-                res.extend_from_slice(n);
-            }
-            if let Some(c) = self.vertex_color.get(v3..v3 + 3) {
-                res.extend_from_slice(c);
-            }
-            if let Some(t) = self.texcoords.get(v2..v2 + 2) {
-                res.extend_from_slice(t);
-            }
-            res
-        }))
+    if runs.is_empty() {
+        return Ok(elements);
     }
 
-    fn triangles(&self) -> Box<dyn Iterator<Item = [u32; 3]> + '_> {
-        match self.face_arities.len() {
-            0 => Box::new(self.indices.chunks_exact(3).map(|c| c.try_into().unwrap()))
-                as Box<dyn Iterator<Item = [u32; 3]> + '_>, // they're already triangles
-            _ => Box::new(
-                self.face_arities
-                    .iter()
-                    .scan(0u32, |i, a| {
-                        let start = *i;
-                        *i = *i + *a;
-                        match a {
-                            0 => unreachable!(),
-                            1 => Some(vec![]), // skip points
-                            2 => Some(vec![]), // skip lines
-                            3 => Some(vec![[start, start + 1, start + 2]]),
-                            4 => Some(vec![
-                                [start, start + 1, start + 2],
-                                [start, start + 2, start + 3],
-                            ]),
-                            x => Some({
-                                let res = vec![];
-                                for c in start + 2..*i {
-                                    res.push([start, c - 1, c]);
-                                }
-                                res
-                            }),
-                        }
-                    })
-                    .flatten(),
-            ),
+    let mut vertices = vec![];
+    let mut colors = false;
+    for line in BufReader::new(File::open(path)?).lines() {
+        let line = line?;
+        let mut words = line.split_whitespace();
+        if words.next() == Some("v") {
+            let vertex = words
+                .map(|w| w.parse::<f32>().unwrap_or(f32::NAN))
+                .collect::<Vec<_>>();
+            colors |= vertex.len() >= 6;
+            vertices.push(vertex);
         }
     }
-
-    fn faces<'mesh>(&'mesh self) -> Box<dyn Iterator<Item = ObjFaceRef<'mesh>> + '_> {
-        match self.face_arities.len() {
-            0 => Box::new(self.indices.chunks_exact(3).scan(0u32, |i, c| {
-                let start = *i;
-                *i = *i + 3;
-                Some(ObjFaceRef::new(self, start..*i))
-            })),
-
-            _ => Box::new(self.face_arities.iter().scan(0u32, |i, a| {
-                let start = *i;
-                *i = *i + *a;
-                Some(ObjFaceRef::new(self, start..*i))
-            })),
+    let mut descriptor = VertexDescriptor::new().with(POSITION, 3);
+    if colors {
+        descriptor = descriptor.with(COLOR, 3);
+    }
+    let length = descriptor.len();
+    for (name, material, indices) in runs {
+        let mut mesh = geom::Mesh::new(descriptor.clone());
+        for i in indices {
+            // tobj fails on bad positions, so only missing ones need skipping here
+            let vertex = match vertices.get(i) {
+                Some(vertex) if vertex.len() >= 3 => vertex,
+                _ => continue,
+            };
+            // vertices without a color, or with a w coordinate instead, are white
+            let color = vertex.get(3..6).unwrap_or(&[1., 1., 1.]);
+            let v = mesh.push_vertex(&[&vertex[0..3], color].concat()[..length]);
+            mesh.push_face(&[v]);
         }
+        elements.points.push(ObjPoints {
+            name,
+            material,
+            mesh,
+        });
     }
+    Ok(elements)
 }
@@ -0,0 +1,337 @@
+use std::{
+    fs::File,
+    io::{BufRead, BufReader},
+    path::Path,
+};
+
+use super::{LoadError, Model, ModelSet, ObjRegistry};
+use crate::geom::{Mesh, VertexDescriptor, COLOR, NORMAL, POSITION};
+
+/// File extensions loaded as point clouds
+pub const POINT_CLOUD_EXTENSIONS: [&str; 3] = ["xyz", "pts", "pcd"];
+
+pub fn is_point_cloud(path: &Path) -> bool {
+    path.extension()
+        .and_then(|e| e.to_str())
+        .is_some_and(|e| POINT_CLOUD_EXTENSIONS.contains(&e.to_ascii_lowercase().as_str()))
+}
+
+impl ObjRegistry {
+    /// Load an ASCII point cloud as a mesh without faces. `columns` is ignored for .pcd files,
+    /// whose header names their fields.
+    pub fn load_points(
+        &mut self,
+        path: impl AsRef<Path>,
+        columns: PointColumns,
+    ) -> Result<(), LoadError> {
+        let path = path.as_ref();
+        tracing::info!("Loading {path:?}");
+        let file = File::open(path).map_err(|e| LoadError::Io(path.to_owned(), e))?;
+        let lines = BufReader::new(file).lines().enumerate().map(|(i, line)| {
+            line.map(|l| (i + 1, l))
+                .map_err(|e| LoadError::Io(path.to_owned(), e))
+        });
+        let extension = path.extension().and_then(|e| e.to_str()).unwrap_or("");
+        let cloud = match extension.to_ascii_lowercase().as_str() {
+            "pcd" => parse_pcd(lines),
+            "pts" => parse_columns(lines, true, columns),
+            _ => parse_columns(lines, false, columns),
+        }
+        .map_err(|e| match e {
+            PointError::Load(e) => e,
+            PointError::Parse(line, message) => LoadError::Points(path.to_owned(), line, message),
+        })?;
+        tracing::info!("Loaded {} points", cloud.mesh.vertex_count());
+
+        self.model_sets.push(ModelSet {
+            models: vec![Model {
+                name: path
+                    .file_stem()
+                    .map_or_else(String::new, |s| s.to_string_lossy().into_owned()),
+                mesh: cloud.mesh,
+                material_id: None,
+            }],
+            ..Default::default()
+        });
+        Ok(())
+    }
+}
+
+enum PointError {
+    Load(LoadError),
+    Parse(usize, String),
+}
+
+impl From<LoadError> for PointError {
+    fn from(e: LoadError) -> Self {
+        Self::Load(e)
+    }
+}
+
+/// Points being read, with their colors kept in whatever range the file uses until it's known
+struct PointCloud {
+    mesh: Mesh,
+    /// Largest color component seen; colors are bytes if any is above 1
+    max_color: f32,
+}
+
+impl PointCloud {
+    fn new(color: bool, normal: bool) -> Self {
+        let mut descriptor = VertexDescriptor::new().with(POSITION, 3);
+        if normal {
+            descriptor = descriptor.with(NORMAL, 3);
+        }
+        if color {
+            descriptor = descriptor.with(COLOR, 3);
+        }
+        Self {
+            mesh: Mesh::new(descriptor),
+            max_color: 0.,
+        }
+    }
+
+    /// Add a point laid out like the mesh's vertices, skipping it if it isn't finite
+    fn push(&mut self, vertex: &[f32]) {
+        if vertex.iter().any(|c| !c.is_finite()) {
+            return;
+        }
+        if let Some(range) = self.mesh.descriptor().field(COLOR) {
+            self.max_color = vertex[range].iter().fold(self.max_color, |m, c| m.max(*c));
+        }
+        self.mesh.push_vertex(vertex);
+    }
+
+    fn finish(mut self) -> Self {
+        if self.max_color > 1. {
+            let range = self.mesh.descriptor().field(COLOR).unwrap();
+            for vertex in self.mesh.vertices_mut() {
+                vertex[range.clone()].iter_mut().for_each(|c| *c /= 255.);
+            }
+        }
+        self
+    }
+}
+
+fn parse_float(word: &str, line: usize) -> Result<f32, PointError> {
+    word.parse()
+        .map_err(|_| PointError::Parse(line, format!("invalid number {word:?}")))
+}
+
+/// What follows x y z in each line of an .xyz or .pts point cloud
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Hash, clap::ValueEnum)]
+pub enum PointColumns {
+    /// Guessed from the first point: colors and/or normals in .xyz files, telling them apart by
+    /// which could be a unit normal, and an intensity before the colors in .pts files
+    Auto,
+    /// Nothing that's read; any further columns are ignored
+    Xyz,
+    /// r g b
+    Rgb,
+    /// nx ny nz
+    Normal,
+    /// r g b nx ny nz
+    RgbNormal,
+    /// nx ny nz r g b
+    NormalRgb,
+    /// intensity r g b, as in Leica .pts files
+    IntensityRgb,
+}
+
+impl Default for PointColumns {
+    fn default() -> Self {
+        Self::Auto
+    }
+}
+
+impl PointColumns {
+    /// Pick the layout of a point split into `words`
+    fn detect(words: &[&str], pts: bool) -> Self {
+        let could_be_normal = |start: usize| {
+            let v = words[start..start + 3]
+                .iter()
+                .map(|w| w.parse::<f32>().unwrap_or(f32::NAN))
+                .collect::<Vec<_>>();
+            // colors are never negative, and normals never above 1
+            v.iter().any(|c| *c < 0.)
+                || v.iter().all(|c| *c <= 1.)
+                    && ((v.iter().map(|c| c * c).sum::<f32>().sqrt() - 1.).abs() < 0.01)
+        };
+        match (pts, words.len()) {
+            (true, 7..) => Self::IntensityRgb,
+            (true, 6) => Self::Rgb,
+            (false, 9..) if could_be_normal(3) && !could_be_normal(6) => Self::NormalRgb,
+            (false, 9..) => Self::RgbNormal,
+            (false, 6..) if could_be_normal(3) => Self::Normal,
+            (false, 6..) => Self::Rgb,
+            _ => Self::Xyz,
+        }
+    }
+
+    /// Columns the colors & normals start at
+    fn layout(self) -> (Option<usize>, Option<usize>) {
+        match self {
+            Self::Auto | Self::Xyz => (None, None),
+            Self::Rgb => (Some(3), None),
+            Self::Normal => (None, Some(3)),
+            Self::RgbNormal => (Some(3), Some(6)),
+            Self::NormalRgb => (Some(6), Some(3)),
+            Self::IntensityRgb => (Some(4), None),
+        }
+    }
+}
+
+/// Parse whitespace or comma separated columns: x y z, followed by whatever `columns` says. Leica
+/// .pts files may start with a point count. Every point must have as many columns as the first.
+fn parse_columns(
+    lines: impl Iterator<Item = Result<(usize, String), LoadError>>,
+    pts: bool,
+    columns: PointColumns,
+) -> Result<PointCloud, PointError> {
+    let mut cloud = None::<(PointCloud, usize)>;
+    let mut color = None;
+    let mut normal = None;
+    let mut vertex = vec![];
+    for line in lines {
+        let (number, line) = line?;
+        let words = line
+            .split(|c: char| c.is_whitespace() || c == ',')
+            .filter(|w| !w.is_empty())
+            .collect::<Vec<_>>();
+        if words.is_empty() || words[0].starts_with('#') || words[0].starts_with("//") {
+            continue;
+        }
+        if pts && words.len() == 1 && cloud.is_none() {
+            continue; // point count
+        }
+        if cloud.is_none() {
+            // the first point decides the layout of every other
+            let columns = match columns {
+                PointColumns::Auto => PointColumns::detect(&words, pts),
+                columns => columns,
+            };
+            (color, normal) = columns.layout();
+            let needed = [color, normal]
+                .into_iter()
+                .flatten()
+                .fold(3, |n, start| n.max(start + 3));
+            if words.len() < needed {
+                return Err(PointError::Parse(
+                    number,
+                    format!("expected at least {needed} columns, found {}", words.len()),
+                ));
+            }
+            tracing::info!("Reading {columns:?} columns after x y z");
+            cloud = Some((
+                PointCloud::new(color.is_some(), normal.is_some()),
+                words.len(),
+            ));
+        }
+        let (cloud, width) = cloud.as_mut().unwrap();
+        if words.len() != *width {
+            return Err(PointError::Parse(
+                number,
+                format!(
+                    "expected {width} columns like the first point, found {}",
+                    words.len()
+                ),
+            ));
+        }
+        vertex.clear();
+        // vertices are laid out as position, normal, color
+        for start in [Some(0), normal, color].into_iter().flatten() {
+            for word in &words[start..start + 3] {
+                vertex.push(parse_float(word, number)?);
+            }
+        }
+        cloud.push(&vertex);
+    }
+    Ok(cloud
+        .map_or_else(|| PointCloud::new(false, false), |(cloud, _)| cloud)
+        .finish())
+}
+
+/// Parse a PCD file with ASCII data, reading positions, normals & packed rgb or rgba colors
+fn parse_pcd(
+    mut lines: impl Iterator<Item = Result<(usize, String), LoadError>>,
+) -> Result<PointCloud, PointError> {
+    let mut fields = vec![];
+    let mut types = vec![];
+    let mut counts = vec![];
+    let mut last = 0;
+    loop {
+        let (number, line) = match lines.next() {
+            Some(line) => line?,
+            None => return Err(PointError::Parse(last, "missing DATA line".into())),
+        };
+        last = number;
+        let mut words = line.split_whitespace();
+        match words.next() {
+            Some("FIELDS") => fields = words.map(str::to_owned).collect(),
+            Some("TYPE") => types = words.map(str::to_owned).collect(),
+            Some("COUNT") => counts = words.map(|w| w.parse().unwrap_or(1)).collect(),
+            Some("DATA") => match words.next() {
+                Some("ascii") => break,
+                Some(format) => {
+                    return Err(PointError::Parse(
+                        number,
+                        format!("{format} data isn't supported; save the cloud as ascii"),
+                    ))
+                }
+                None => return Err(PointError::Parse(number, "missing data format".into())),
+            },
+            _ => {}
+        }
+    }
+
+    // column each field starts at, since fields may span several columns
+    let mut column = 0;
+    let mut columns = vec![];
+    for i in 0..fields.len() {
+        columns.push(column);
+        column += counts.get(i).copied().unwrap_or(1);
+    }
+    let find = |name: &str| fields.iter().position(|f| f == name);
+    let position = ["x", "y", "z"]
+        .map(find)
+        .into_iter()
+        .collect::<Option<Vec<_>>>()
+        .ok_or_else(|| PointError::Parse(last, "missing x, y or z field".into()))?;
+    let normal = ["normal_x", "normal_y", "normal_z"]
+        .map(find)
+        .into_iter()
+        .collect::<Option<Vec<_>>>();
+    let color = find("rgb").or_else(|| find("rgba"));
+
+    let mut cloud = PointCloud::new(color.is_some(), normal.is_some());
+    let mut vertex = vec![];
+    for line in lines {
+        let (number, line) = line?;
+        let words = line.split_whitespace().collect::<Vec<_>>();
+        if words.is_empty() {
+            continue;
+        }
+        if words.len() < column {
+            return Err(PointError::Parse(
+                number,
+                format!("expected {column} columns, found {}", words.len()),
+            ));
+        }
+        vertex.clear();
+        for field in position.iter().chain(normal.iter().flatten()) {
+            vertex.push(parse_float(words[columns[*field]], number)?);
+        }
+        if let Some(field) = color {
+            let word = words[columns[field]];
+            // colors are packed into the bits of a float, or an unsigned integer
+            let packed = match types.get(field).map(String::as_str) {
+                Some("U") | Some("I") => word.parse::<u32>().ok(),
+                _ => word.parse::<f32>().ok().map(f32::to_bits),
+            }
+            .ok_or_else(|| PointError::Parse(number, format!("invalid color {word:?}")))?;
+            let [b, g, r, _] = packed.to_le_bytes();
+            vertex.extend([r, g, b].map(|c| c as f32 / 255.));
+        }
+        cloud.push(&vertex);
+    }
+    Ok(cloud)
+}
@@ -0,0 +1,261 @@
+use std::collections::HashMap;
+
+use nalgebra::Vector3;
+
+use super::ModelSet;
+use crate::geom::Mesh;
+
+/// Largest squared sine of the angle between two edges of a triangle counted as degenerate. Rounding
+/// f32 coordinates far from the origin can tilt collinear points this far (about 0.006°) apart.
+const DEGENERATE_SIN2: f32 = 1e-8;
+
+/// Optional fixes applied by [`ModelSet::validate`]. Faces that would crash voxelization, with
+/// out-of-range indices or non-finite coordinates, are always dropped.
+#[derive(Debug, Default, PartialEq, Clone, Copy)]
+pub struct RepairOptions {
+    /// Drop faces with no area
+    pub drop_degenerate: bool,
+    /// Merge vertices that round to the same point on a grid this many model units apart
+    pub weld_distance: Option<f32>,
+    /// Fill holes bounded by at most this many edges
+    pub max_hole_edges: usize,
+}
+
+/// Problems found in a model set, counted after any repairs
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct MeshReport {
+    /// Faces dropped for referencing missing vertices, normals or texture coordinates
+    pub bad_indices: usize,
+    /// Faces dropped for having NaN or infinite coordinates
+    pub non_finite: usize,
+    pub degenerate: usize,
+    pub welded: usize,
+    pub holes_closed: usize,
+    /// Edges used by only one face
+    pub boundary_edges: usize,
+    /// Edges shared by more than two faces
+    pub non_manifold_edges: usize,
+    /// Edges shared by two faces winding the same way, as when one of them is flipped
+    pub flipped_edges: usize,
+}
+
+impl ModelSet {
+    /// Check every mesh for problems that would break voxelization or make it unreliable,
+    /// logging them & applying the fixes enabled in `repair`. Polygons in meshes that are changed
+    /// are rewritten as triangles.
+    pub fn validate(&mut self, repair: &RepairOptions) -> MeshReport {
+        let mut total = MeshReport::default();
+        // point clouds have no topology to check
+        for model in self.models.iter_mut().filter(|m| m.mesh.has_faces()) {
+            let report = validate_mesh(&mut model.mesh, repair);
+            log_report(&model.name, &report, repair);
+            total.bad_indices += report.bad_indices;
+            total.non_finite += report.non_finite;
+            total.degenerate += report.degenerate;
+            total.welded += report.welded;
+            total.holes_closed += report.holes_closed;
+            total.boundary_edges += report.boundary_edges;
+            total.non_manifold_edges += report.non_manifold_edges;
+            total.flipped_edges += report.flipped_edges;
+        }
+        total
+    }
+}
+
+fn log_report(name: &str, report: &MeshReport, repair: &RepairOptions) {
+    if report.bad_indices > 0 {
+        tracing::warn!(
+            "{name}: dropped {} faces with out-of-range indices",
+            report.bad_indices
+        );
+    }
+    if report.non_finite > 0 {
+        tracing::warn!(
+            "{name}: dropped {} faces with NaN or infinite coordinates",
+            report.non_finite
+        );
+    }
+    if report.degenerate > 0 {
+        match repair.drop_degenerate {
+            true => tracing::info!("{name}: dropped {} degenerate faces", report.degenerate),
+            false => tracing::warn!("{name}: {} degenerate faces", report.degenerate),
+        }
+    }
+    if report.welded > 0 {
+        tracing::info!("{name}: welded {} vertices", report.welded);
+    }
+    if report.holes_closed > 0 {
+        tracing::info!("{name}: closed {} holes", report.holes_closed);
+    }
+    if report.boundary_edges > 0 {
+        tracing::debug!("{name}: {} open boundary edges", report.boundary_edges);
+    }
+    if report.non_manifold_edges > 0 {
+        tracing::warn!("{name}: {} non-manifold edges", report.non_manifold_edges);
+    }
+    if report.flipped_edges > 0 {
+        tracing::warn!(
+            "{name}: {} edges between faces with opposite winding",
+            report.flipped_edges
+        );
+    }
+}
+
+fn validate_mesh(mesh: &mut Mesh, repair: &RepairOptions) -> MeshReport {
+    let mut report = MeshReport::default();
+    let vertex_count = mesh.vertex_count();
+
+    let mut changed = false;
+    // Points & lines are kept as they are, and polygons are split into triangles
+    let mut others = vec![];
+    let mut triangles = vec![];
+    for face in mesh.faces() {
+        if face.indices().iter().any(|i| *i as usize >= vertex_count) {
+            report.bad_indices += 1;
+            changed = true;
+            continue;
+        }
+        if face.vertices().any(|v| v.iter().any(|c| !c.is_finite())) {
+            report.non_finite += 1;
+            changed = true;
+            continue;
+        }
+        match face.len() {
+            0..=2 => others.push(face.indices().to_vec()),
+            _ => triangles.extend(face.triangles().iter().map(|t| t.indices())),
+        }
+    }
+
+    // Vertices split apart by texture seams share a position, so topology is checked between
+    // positions rather than vertices. Welding maps every position onto the first one found in
+    // its cell, and snaps the vertices there.
+    let mut ids = (0..vertex_count as u32).collect::<Vec<_>>();
+    let mut exact = HashMap::<[u32; 3], u32>::new();
+    let mut cells = HashMap::<Vector3<i64>, u32>::new();
+    let weld_distance = repair.weld_distance.filter(|d| *d > 0.);
+    for v in 0..vertex_count as u32 {
+        let p = mesh.position(v as usize);
+        if !p.iter().all(|c| c.is_finite()) {
+            continue;
+        }
+        let first = *exact.entry(p.map(f32::to_bits).into()).or_insert(v);
+        ids[v as usize] = first;
+        if let Some(distance) = weld_distance {
+            let cell = p.map(|c| (c / distance).round() as i64);
+            let welded = *cells.entry(cell).or_insert(first);
+            if welded != first {
+                ids[v as usize] = welded;
+                report.welded += (first == v) as usize;
+                mesh.set_position(v as usize, mesh.position(welded as usize));
+                changed = true;
+            }
+        }
+    }
+    let id = |v: u32| ids[v as usize];
+
+    let is_degenerate = |t: &[u32; 3]| {
+        let [a, b, c] = t.map(id);
+        let [pa, pb, pc] = t.map(|v| mesh.position(v as usize));
+        let (u, v) = (pb - pa, pc - pa);
+        // |u × v|² = |u|² |v|² sin² of the angle between them
+        a == b
+            || b == c
+            || c == a
+            || u.cross(&v).norm_squared() <= DEGENERATE_SIN2 * u.norm_squared() * v.norm_squared()
+    };
+    let before = triangles.len();
+    match repair.drop_degenerate {
+        true => triangles.retain(|t| !is_degenerate(t)),
+        false => report.degenerate = triangles.iter().filter(|t| is_degenerate(t)).count(),
+    }
+    if triangles.len() != before {
+        report.degenerate = before - triangles.len();
+        changed = true;
+    }
+
+    // Directed edges between positions, with the vertices they start at
+    let mut edges = HashMap::<(u32, u32), Vec<u32>>::new();
+    for t in &triangles {
+        for i in 0..3 {
+            let (a, b) = (t[i], t[(i + 1) % 3]);
+            if id(a) != id(b) {
+                edges.entry((id(a), id(b))).or_default().push(a);
+            }
+        }
+    }
+    let mut holes = vec![];
+    if repair.max_hole_edges >= 3 {
+        holes = find_holes(&edges, repair.max_hole_edges);
+        report.holes_closed = holes.len();
+        changed |= !holes.is_empty();
+    }
+
+    for ((a, b), used) in &edges {
+        let reverse = edges.get(&(*b, *a)).map_or(0, |r| r.len());
+        let uses = used.len() + reverse;
+        // count each undirected edge once, from its lower vertex
+        if a < b || reverse == 0 {
+            match uses {
+                1 => report.boundary_edges += 1,
+                2 => report.flipped_edges += (reverse == 0) as usize,
+                _ => report.non_manifold_edges += 1,
+            }
+        }
+    }
+    if !holes.is_empty() {
+        report.boundary_edges -= holes.iter().map(|h| h.len()).sum::<usize>();
+    }
+
+    if changed {
+        rebuild(mesh, &others, &triangles, &holes);
+    }
+    report
+}
+
+/// Loops of boundary edges with at most `max_edges` edges, as the vertices their edges start at
+fn find_holes(edges: &HashMap<(u32, u32), Vec<u32>>, max_edges: usize) -> Vec<Vec<u32>> {
+    // A hole runs against the winding of the faces around it, so it follows boundary edges
+    // backwards
+    let mut next = HashMap::<u32, Vec<(u32, u32)>>::new();
+    for ((a, b), used) in edges {
+        if used.len() == 1 && !edges.contains_key(&(*b, *a)) {
+            next.entry(*b).or_default().push((*a, used[0]));
+        }
+    }
+    let mut starts = next.keys().copied().collect::<Vec<_>>();
+    starts.sort_unstable();
+
+    let mut holes = vec![];
+    for start in starts {
+        let mut hole = vec![];
+        let mut vertex = start;
+        while let Some((to, from)) = next.get_mut(&vertex).and_then(|n| n.pop()) {
+            hole.push(from);
+            vertex = to;
+            if vertex == start || hole.len() > max_edges {
+                break;
+            }
+        }
+        if vertex == start && (3..=max_edges).contains(&hole.len()) {
+            holes.push(hole);
+        }
+    }
+    holes
+}
+
+/// Replace the mesh's faces with `others` & `triangles`, plus a fan of triangles over each hole
+fn rebuild(mesh: &mut Mesh, others: &[Vec<u32>], triangles: &[[u32; 3]], holes: &[Vec<u32>]) {
+    mesh.clear_faces();
+    for face in others {
+        mesh.push_face(face);
+    }
+    for t in triangles {
+        mesh.push_face(t);
+    }
+    for hole in holes {
+        // boundary edges were followed backwards, so the hole already winds like its neighbors
+        for i in 1..hole.len() - 1 {
+            mesh.push_face(&[hole[0], hole[i], hole[i + 1]]);
+        }
+    }
+}
@@ -1,32 +1,42 @@
 mod barycentric;
+mod catalog;
 mod cli;
 mod color;
+mod dda;
 // mod gui;
 mod geom;
+mod grid;
 mod icon;
 mod intersect;
 mod load;
 mod octree;
+mod orientation;
 mod palette;
 mod rampify;
 mod simplify;
+mod smooth;
+mod store;
+mod stream;
+mod texture;
 mod voxelize;
 
 use brickadia as brs;
 use brs::save::Preview;
+use catalog::CatalogBrick;
 use clap::{CommandFactory, Parser};
 use eframe::{egui, egui::*, run_native, App, NativeOptions};
-use rayon::prelude::{IntoParallelIterator, IntoParallelRefIterator, ParallelIterator};
+use rayon::prelude::{IntoParallelRefIterator, ParallelIterator};
 // use gui::bool_color;
 use rfd::FileDialog;
 use simplify::*;
 use std::{
     fs::File,
+    io::BufReader,
     ops::RangeInclusive,
     path::{Path, PathBuf},
 };
 use uuid::Uuid;
-use voxelize::voxelize;
+use voxelize::{Voxel, VoxelizeOptions};
 
 const OBJ_ICON: &[u8; 10987] = include_bytes!("../res/obj_icon.png");
 
@@ -45,6 +55,52 @@ impl BrickType {
             _ => Scale3::new(1.0, 2.5, 1.0),
         }
     }
+
+    pub fn asset_name(&self) -> &'static str {
+        match self {
+            Self::Microbricks => "PB_DefaultMicroBrick",
+            Self::Default => "PB_DefaultBrick",
+            Self::Tiles => "PB_DefaultTile",
+        }
+    }
+
+    /// Size of one voxel along the x, y & z axes of a brick, in save units
+    pub fn voxel_size(&self) -> (isize, isize, isize) {
+        match self {
+            Self::Microbricks => (1, 1, 1),
+            _ => (5, 5, 2),
+        }
+    }
+
+    /// Most voxels a single brick can span along each of its axes
+    pub fn max_voxels(&self) -> (isize, isize, isize) {
+        asset_max_voxels(self.asset_name(), self.voxel_size())
+    }
+}
+
+/// Largest half-size of each procedural brick asset along its x, y & z axes, in save units.
+/// Brickadia doesn't document its per-asset limits, so until they're checked in game each axis
+/// keeps the 500-unit (100 studs across) cap this converter has always used. Voxel counts still
+/// differ by asset, as studded bricks are 5×5×2 units per voxel & microbricks 1×1×1.
+const ASSET_MAX_SIZES: [(&str, (u32, u32, u32)); 3] = [
+    ("PB_DefaultMicroBrick", (500, 500, 500)),
+    ("PB_DefaultBrick", (500, 500, 500)),
+    ("PB_DefaultTile", (500, 500, 500)),
+];
+
+/// Largest size Brickadia accepts for a procedural `asset`, in save units
+pub fn asset_max_size(asset: &str) -> (u32, u32, u32) {
+    ASSET_MAX_SIZES
+        .iter()
+        .find(|(name, _)| *name == asset)
+        .map_or(ASSET_MAX_SIZES[0].1, |(_, size)| *size)
+}
+
+/// Most voxels of `voxel_size` a brick of `asset` can span along each of its axes
+pub fn asset_max_voxels(asset: &str, voxel_size: (isize, isize, isize)) -> (isize, isize, isize) {
+    let (x, y, z) = asset_max_size(asset);
+    let (sx, sy, sz) = voxel_size;
+    (x as isize / sx, y as isize / sy, z as isize / sz)
 }
 
 #[derive(Debug, PartialEq, Eq, Clone, Copy, Hash, clap::ValueEnum)]
@@ -57,77 +113,63 @@ pub enum Material {
     Ghost,
 }
 
-#[derive(thiserror::Error, Debug)]
-pub enum ConversionError {
-    #[error(transparent)]
-    LoadObj(#[from] tobj::LoadError),
-    #[error("Failed to load {0} texture file from {1:?}: {2}")]
-    LoadImg(String, PathBuf, image::ImageError),
+/// How partially transparent texels are converted
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Hash, clap::ValueEnum)]
+pub enum AlphaMode {
+    /// Keep the alpha of each voxel in its brick color
+    Blend,
+    /// Make every voxel above the alpha cutoff fully opaque
+    Cutout,
+    /// Use glass bricks for partially transparent voxels
+    Glass,
 }
 
-fn load_model(path: &Path) -> Result<(Vec<tobj::Model>, Vec<image::RgbaImage>), ConversionError> {
-    tracing::info!("Loading {path:?}");
-    tracing::info!("Importing model...");
-    let (models, materials) = match tobj::load_obj(
-        path,
-        &tobj::LoadOptions {
-            triangulate: true,
-            ..Default::default()
-        },
-    ) {
-        Err(e) | Ok((_, Err(e))) => return Err(e.into()),
-        Ok((models, Ok(materials))) => (models, materials),
-    };
-    tracing::info!("Loading materials...");
-    let mut material_images = Vec::<image::RgbaImage>::new();
-    for material in materials {
-        if material.diffuse_texture.is_empty() {
-            tracing::info!(
-                "\tMaterial {} does not have an associated diffuse texture",
-                material.name
-            );
-
-            // Create mock texture from diffuse color
-            let mut image = image::RgbaImage::new(1, 1);
-
-            image.put_pixel(
-                0,
-                0,
-                image::Rgba([
-                    color::ftoi(material.diffuse[0]),
-                    color::ftoi(material.diffuse[1]),
-                    color::ftoi(material.diffuse[2]),
-                    color::ftoi(material.dissolve),
-                ]),
-            );
-
-            material_images.push(image);
-        } else {
-            let image_path = path.parent().unwrap().join(&material.diffuse_texture);
-            tracing::info!(
-                "\tLoading diffuse texture for {} from: {:?}",
-                material.name,
-                image_path
-            );
-
-            let image = image::open(&image_path)
-                .map_err(|e| ConversionError::LoadImg(material.diffuse_texture, image_path, e))?
-                .into_rgba8();
-            material_images.push(image);
-        }
+impl Default for AlphaMode {
+    fn default() -> Self {
+        Self::Blend
     }
-    Ok((models, material_images))
 }
 
-#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash)]
+#[derive(thiserror::Error, Debug)]
+pub enum ConversionError {
+    #[error(transparent)]
+    Load(#[from] load::LoadError),
+    #[error("Failed to write tiles to disk: {0}")]
+    Tiles(#[from] std::io::Error),
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
 pub enum ConversionOptions {
     Simplify {
         lossless: bool,
         match_default_colorset: bool,
         bricktype: BrickType,
         max_merge: u32,
+        merge_strategy: MergeStrategy,
+        color_tolerance: Option<f32>,
+        standard_sizes: bool,
+        catalog: bool,
+        smooth: bool,
+        orient_to_surface: bool,
+    },
+    Rampify {
+        match_default_colorset: bool,
+        bricktype: BrickType,
+        ramps: rampify::RampOptions,
+        orient_to_surface: bool,
     },
-    Rampify {},
+}
+
+/// Index of `asset` in the save's brick assets, adding it if it isn't there yet
+pub fn asset_index(data: &mut brs::save::SaveData, asset: &str) -> u32 {
+    let assets = &mut data.header2.brick_assets;
+    match assets.iter().position(|a| a == asset) {
+        Some(index) => index as u32,
+        None => {
+            assets.push(asset.into());
+            (assets.len() - 1) as u32
+        }
+    }
 }
 
 fn start_brs_data(owner: &brs::save::User, material: Material) -> brs::save::SaveData {
@@ -162,19 +204,84 @@ fn start_brs_data(owner: &brs::save::User, material: Material) -> brs::save::Sav
 }
 
 fn write_octree(
-    octree: &mut octree::VoxelTree<Vector4<u8>>,
+    octree: &mut dyn store::VoxelStore<Voxel>,
     write_data: &mut brs::save::SaveData,
     material_intensity: u32,
     options: ConversionOptions,
+    vox_opts: &VoxelizeOptions,
+    catalog: &[CatalogBrick],
 ) {
+    let glass_material = match vox_opts.alpha_mode {
+        AlphaMode::Glass => Some(
+            match write_data
+                .header2
+                .materials
+                .iter()
+                .position(|m| m == "BMC_Glass")
+            {
+                Some(index) => index as u32,
+                None => {
+                    write_data.header2.materials.push("BMC_Glass".into());
+                    (write_data.header2.materials.len() - 1) as u32
+                }
+            },
+        ),
+        _ => None,
+    };
+
     if let ConversionOptions::Simplify {
         bricktype: BrickType::Tiles,
         ..
+    }
+    | ConversionOptions::Rampify {
+        bricktype: BrickType::Tiles,
+        ..
     } = options
     {
         write_data.header2.brick_assets[1] = "PB_DefaultTile".into();
     }
 
+    let brick_count = write_data.bricks.len();
+    let normals = match options {
+        ConversionOptions::Simplify {
+            orient_to_surface: true,
+            bricktype: BrickType::Default | BrickType::Tiles,
+            ..
+        } => Some(orientation::collect_normals(octree)),
+        ConversionOptions::Simplify {
+            orient_to_surface: true,
+            ..
+        } => {
+            tracing::warn!("Orienting to the surface only affects default bricks & tiles");
+            None
+        }
+        ConversionOptions::Rampify {
+            orient_to_surface: true,
+            ..
+        } => Some(orientation::collect_normals(octree)),
+        _ => None,
+    };
+    if let ConversionOptions::Simplify {
+        smooth: true,
+        bricktype,
+        match_default_colorset,
+        ..
+    } = options
+    {
+        if bricktype == BrickType::Microbricks {
+            tracing::info!("Smoothing...");
+            smooth::smooth_microwedges(
+                octree,
+                write_data,
+                match_default_colorset,
+                material_intensity,
+                glass_material,
+            );
+        } else {
+            tracing::warn!("Smoothing can only be used with the microbricks bricktype");
+        }
+    }
+
     tracing::info!("Simplifying...");
     match options {
         ConversionOptions::Simplify {
@@ -182,6 +289,8 @@ fn write_octree(
             max_merge,
             match_default_colorset,
             bricktype,
+            merge_strategy: MergeStrategy::Greedy,
+            ..
         } => simplify_lossless(
             octree,
             write_data,
@@ -189,12 +298,31 @@ fn write_octree(
             bricktype,
             material_intensity,
             max_merge as isize,
+            glass_material,
+        ),
+        ConversionOptions::Simplify {
+            lossless: true,
+            max_merge,
+            match_default_colorset,
+            bricktype,
+            merge_strategy: MergeStrategy::Optimal,
+            ..
+        } => simplify_lossless_optimal(
+            octree,
+            write_data,
+            match_default_colorset,
+            bricktype,
+            material_intensity,
+            max_merge as isize,
+            glass_material,
         ),
         ConversionOptions::Simplify {
             lossless: false,
             max_merge,
             match_default_colorset,
             bricktype,
+            color_tolerance,
+            ..
         } => simplify_lossy(
             octree,
             write_data,
@@ -202,23 +330,60 @@ fn write_octree(
             bricktype,
             material_intensity,
             max_merge as isize,
+            vox_opts.averaging,
+            glass_material,
+            color_tolerance,
         ),
-        ConversionOptions::Rampify { .. } => {
+        ConversionOptions::Rampify {
+            match_default_colorset,
+            bricktype,
+            ramps,
+            ..
+        } => {
             simplify_lossless(
                 octree,
                 write_data,
-                true,
-                BrickType::Default,
+                match_default_colorset,
+                bricktype,
                 material_intensity,
                 1,
+                glass_material,
             );
-            rampify::rampify(write_data);
+            rampify::rampify(write_data, bricktype, ramps, normals.as_ref());
         }
     }
+    if let ConversionOptions::Simplify {
+        standard_sizes: true,
+        bricktype: bricktype @ (BrickType::Default | BrickType::Tiles),
+        ..
+    } = options
+    {
+        tracing::info!("Splitting into standard brick sizes...");
+        standardize_bricks(write_data, brick_count, bricktype);
+    }
+    match options {
+        ConversionOptions::Simplify {
+            catalog: true,
+            bricktype: BrickType::Default,
+            ..
+        } => {
+            tracing::info!("Filling with catalog bricks...");
+            catalog::fill_catalog(write_data, brick_count, catalog);
+        }
+        ConversionOptions::Simplify { catalog: true, .. } => {
+            tracing::warn!("Catalog bricks can only be used with the default bricktype")
+        }
+        _ => {}
+    }
+    if let (Some(normals), ConversionOptions::Simplify { bricktype, .. }) = (normals, options) {
+        tracing::info!("Orienting bricks to the surface...");
+        orientation::orient_to_normals(write_data, brick_count, bricktype, &normals);
+    }
+    tracing::info!("Generated {} bricks", write_data.bricks.len() - brick_count);
 }
 
-fn raise_brs(data: &mut brs::save::SaveData) {
-    tracing::info!("Raising...");
+/// Bottom of the lowest brick, or 0 if none are below the ground
+fn brs_floor(data: &brs::save::SaveData) -> i32 {
     let mut min_z = 0;
     for brick in &data.bricks {
         let height = match brick.size {
@@ -230,16 +395,34 @@ fn raise_brs(data: &mut brs::save::SaveData) {
             min_z = z;
         }
     }
+    min_z
+}
 
+fn raise_brs(data: &mut brs::save::SaveData, floor: i32) {
+    tracing::info!("Raising...");
     for brick in &mut data.bricks {
-        brick.position.2 -= min_z;
+        brick.position.2 -= floor;
     }
 }
 
-fn write_brs(data: brs::save::SaveData, path: &Path) {
+fn write_brs(data: brs::save::SaveData, path: &Path) -> Result<(), WriteError> {
     // Write file
     tracing::info!("Writing {} bricks to {path:?}...", data.bricks.len());
+    let file = File::create(path).map_err(|e| WriteError::Io(path.to_owned(), e))?;
+    brs::write::SaveWriter::new(file, data)
+        .write()
+        .map_err(|e| WriteError::Save(path.to_owned(), e))?;
     tracing::info!("Save Written!");
+    Ok(())
+}
+
+fn read_brs(path: &Path) -> Result<brs::save::SaveData, WriteError> {
+    let file = File::open(path).map_err(|e| WriteError::Io(path.to_owned(), e))?;
+    let read_error = |e| WriteError::Read(path.to_owned(), e);
+    brs::read::SaveReader::new(BufReader::new(file))
+        .map_err(read_error)?
+        .read_all()
+        .map_err(read_error)
 }
 
 #[derive(Debug, Copy, Clone, Eq, PartialEq, Hash, clap::ValueEnum)]
@@ -284,14 +467,42 @@ lazy_static::lazy_static! {
     };
 }
 
-fn voxelize_obj(
+fn load_model(
+    path: &Path,
+    repair: &load::RepairOptions,
+    point_columns: load::PointColumns,
+) -> Result<load::ObjRegistry, ConversionError> {
+    let mut registry = load::ObjRegistry::default();
+    match load::is_point_cloud(path) {
+        true => registry.load_points(path, point_columns)?,
+        false => registry.load(
+            path,
+            &tobj::LoadOptions {
+                // polygons are ear clipped by `geom::Mesh::triangles`, which unlike tobj's fans
+                // keeps concave faces intact
+                triangulate: false,
+                ..Default::default()
+            },
+        )?,
+    }
+    tracing::info!("Validating meshes...");
+    for set in &mut registry.model_sets {
+        set.validate(repair);
+    }
+    Ok(registry)
+}
+
+fn voxelize_model(
     path: &Path,
     scale: f32,
     bricktype: BrickType,
-) -> Result<octree::VoxelTree<Vector4<u8>>, ConversionError> {
+    vox_opts: &VoxelizeOptions,
+    repair: &load::RepairOptions,
+    point_columns: load::PointColumns,
+) -> Result<Box<dyn store::VoxelStore<Voxel>>, ConversionError> {
+    let mut registry = load_model(path, repair, point_columns)?;
     tracing::info!("Voxelizing {path:?}");
-    let (mut models, material_images) = load_model(path)?; //.expect(&format!("Failed to load input model: {input:?}"));
-    Ok(voxelize(&mut models, &material_images, scale, bricktype))
+    Ok(registry.voxelize(scale, bricktype, vox_opts))
 }
 
 #[derive(Debug, thiserror::Error)]
@@ -300,6 +511,92 @@ pub enum WriteError {
     Collision,
     #[error(transparent)]
     Conversion(#[from] ConversionError),
+    #[error("Failed to write {0:?}: {1}")]
+    Io(PathBuf, std::io::Error),
+    #[error("Failed to write {0:?}: {1}")]
+    Save(PathBuf, brs::write::WriteError),
+    #[error("Failed to read back {0:?}: {1}")]
+    Read(PathBuf, brs::read::ReadError),
+}
+
+impl From<std::io::Error> for WriteError {
+    fn from(e: std::io::Error) -> Self {
+        Self::Conversion(e.into())
+    }
+}
+
+/// Rough memory held by each brick waiting to be written, counting its components
+const BYTES_PER_BRICK: u64 = 256;
+
+/// Bricks converted within a memory budget. Once those waiting to be written outgrow their share
+/// of the budget, they're written out as numbered parts next to the output (`model.1.brs`,
+/// `model.2.brs`, ...), which load together in game.
+struct BrickParts<'a> {
+    output: &'a Path,
+    overwrite: bool,
+    brick_cap: usize,
+    /// Path & floor of each part written so far
+    parts: Vec<(PathBuf, i32)>,
+}
+
+impl BrickParts<'_> {
+    /// Write the bricks in `data` out as the next part once there are more than `brick_cap`
+    fn spill(&mut self, data: &mut brs::save::SaveData) -> Result<(), WriteError> {
+        if data.bricks.len() > self.brick_cap {
+            self.write_part(data)?;
+        }
+        Ok(())
+    }
+
+    fn write_part(&mut self, data: &mut brs::save::SaveData) -> Result<(), WriteError> {
+        let stem = self.output.file_stem().unwrap().to_string_lossy();
+        let path = self
+            .output
+            .with_file_name(format!("{stem}.{}.brs", self.parts.len() + 1));
+        match (path.exists(), self.overwrite) {
+            (false, _) => {}
+            (true, false) => return Err(WriteError::Collision),
+            (true, true) => tracing::warn!("Overwriting {path:?}"),
+        }
+        let next = brs::save::SaveData {
+            header1: data.header1.clone(),
+            header2: data.header2.clone(),
+            preview: data.preview.clone(),
+            ..Default::default()
+        };
+        let part = std::mem::replace(data, next);
+        self.parts.push((path.clone(), brs_floor(&part)));
+        write_brs(part, &path)
+    }
+
+    /// Write the remaining bricks, to the output itself if nothing was split off
+    fn finish(mut self, mut data: brs::save::SaveData, raise: bool) -> Result<(), WriteError> {
+        if self.parts.is_empty() {
+            if raise {
+                let floor = brs_floor(&data);
+                raise_brs(&mut data, floor);
+            }
+            return write_brs(data, self.output);
+        }
+        if !data.bricks.is_empty() {
+            self.write_part(&mut data)?;
+        }
+        tracing::warn!(
+            "Bricks were split across {} parts next to {:?} to stay within the memory budget",
+            self.parts.len(),
+            self.output
+        );
+        let floor = self.parts.iter().map(|&(_, floor)| floor).min().unwrap();
+        if raise && floor < 0 {
+            // parts are raised together once all are written, so they keep lining up
+            for (path, _) in &self.parts {
+                let mut part = read_brs(path)?;
+                raise_brs(&mut part, floor);
+                write_brs(part, path)?;
+            }
+        }
+        Ok(())
+    }
 }
 
 fn write_objs_to_brs(
@@ -308,7 +605,12 @@ fn write_objs_to_brs(
     material_intensity: u32,
     raise: bool,
     scale: f32,
+    vox_opts: VoxelizeOptions,
+    repair: load::RepairOptions,
+    point_columns: load::PointColumns,
     conv_opts: ConversionOptions,
+    catalog: &[CatalogBrick],
+    memory_budget: Option<u64>,
     preview: Preview,
     overwrite: bool,
     inputs: &[impl AsRef<Path>],
@@ -321,26 +623,73 @@ fn write_objs_to_brs(
         (true, true) => tracing::warn!("Overwriting {output:?}"),
     }
     let mut data = start_brs_data(&owner, material);
+    data.preview = preview;
+    let bricktype = match conv_opts {
+        ConversionOptions::Simplify { bricktype, .. }
+        | ConversionOptions::Rampify { bricktype, .. } => bricktype,
+    };
+    let memory_budget = match (memory_budget, conv_opts) {
+        (Some(_), ConversionOptions::Rampify { .. }) => {
+            tracing::warn!("Rampify needs the whole model at once; ignoring the memory budget");
+            None
+        }
+        (Some(_), ConversionOptions::Simplify { smooth: true, .. }) => {
+            tracing::warn!(
+                "Voxels along tile seams may be smoothed as if their neighbors were empty"
+            );
+            memory_budget
+        }
+        _ => memory_budget,
+    };
+    let mut parts = BrickParts {
+        output,
+        overwrite,
+        brick_cap: memory_budget.map_or(usize::MAX, |budget| {
+            // a quarter of the budget is kept for bricks, the rest goes to voxelizing tiles
+            ((budget << 20) / 4 / BYTES_PER_BRICK) as usize
+        }),
+        parts: vec![],
+    };
     for input in inputs.iter().map(|p| p.as_ref()) {
         tracing::info!("Adding {input:?} to brs data");
-        let mut octree = voxelize_obj(
-            input,
-            scale,
-            match conv_opts {
-                ConversionOptions::Rampify {} => BrickType::Default,
-                ConversionOptions::Simplify { bricktype, .. } => bricktype,
-            },
-        )?;
-        write_octree(&mut octree, &mut data, material_intensity, conv_opts);
-    }
-    if raise {
-        raise_brs(&mut data);
+        match memory_budget {
+            Some(budget) => {
+                let registry = load_model(input, &repair, point_columns)?;
+                tracing::info!("Voxelizing {input:?} in tiles");
+                stream::voxelize_tiled(
+                    registry,
+                    scale,
+                    bricktype,
+                    &vox_opts,
+                    (budget << 20) / 4 * 3,
+                    |store| {
+                        write_octree(
+                            store,
+                            &mut data,
+                            material_intensity,
+                            conv_opts,
+                            &vox_opts,
+                            catalog,
+                        );
+                        parts.spill(&mut data)
+                    },
+                )?;
+            }
+            None => {
+                let mut octree =
+                    voxelize_model(input, scale, bricktype, &vox_opts, &repair, point_columns)?;
+                write_octree(
+                    octree.as_mut(),
+                    &mut data,
+                    material_intensity,
+                    conv_opts,
+                    &vox_opts,
+                    catalog,
+                );
+            }
+        }
     }
-    data.preview = preview;
-    brs::write::SaveWriter::new(File::create(output).unwrap(), data)
-        .write()
-        .unwrap();
-    Ok(())
+    parts.finish(data, raise)
 }
 
 fn main() {
@@ -370,9 +719,12 @@ fn main() {
             id: args.owner_id,
         };
         let conv_opts = args.command.as_ref().unwrap().as_conversion_options();
+        let vox_opts = args.voxelize_options();
+        let repair = args.repair_options();
+        let catalog = args.command.as_ref().unwrap().catalog_bricks();
         if args.output.is_dir() {
             // write all converted inputs to separate files in args.output
-            args.inputs().into_par_iter().for_each(|input| {
+            let convert = |input: &PathBuf| {
                 let file_name = input.file_stem().unwrap().to_str().unwrap().to_owned();
                 let file_path = args.output.join(format!("{}.brs", file_name));
                 tracing::info!("Generating {file_name:?}.brs...");
@@ -382,7 +734,12 @@ fn main() {
                     args.material_intensity,
                     args.raise,
                     args.scale,
+                    vox_opts,
+                    repair,
+                    args.point_columns,
                     conv_opts,
+                    &catalog,
+                    args.memory_budget,
                     Preview::PNG(PREVIEW_BYTES.clone()),
                     args.overwrite,
                     &[input],
@@ -394,7 +751,12 @@ fn main() {
                     }
                     Err(e) => tracing::error!("{e:?}"),
                 };
-            });
+            };
+            match args.memory_budget {
+                // each input is given the whole budget, so they're converted one at a time
+                Some(_) => args.inputs().iter().for_each(convert),
+                None => args.inputs().par_iter().for_each(convert),
+            }
         } else {
             // write all converted inputs to a single file (args.output)
             tracing::info!("Generating {0:?}...", args.output);
@@ -404,7 +766,12 @@ fn main() {
                 args.material_intensity,
                 args.raise,
                 args.scale,
+                vox_opts,
+                repair,
+                args.point_columns,
                 conv_opts,
+                &catalog,
+                args.memory_budget,
                 Preview::PNG(PREVIEW_BYTES.clone()),
                 args.overwrite,
                 args.inputs(),
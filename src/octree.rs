@@ -1,6 +1,7 @@
 //! Octree rewrite partially inspired by parry's QBVH type (that one partitions space into
 //! multiples of 4 rather than 8, though)
 
+use nalgebra::{Point3, Vector3};
 use parry3d::bounding_volume::AABB;
 use std::{
     marker::PhantomData,
@@ -15,12 +16,39 @@ pub enum Error {
     OutOfRange(u8),
     #[error("Attempted to access child of terminal node")]
     NoChildren,
+    #[error("Child {0} is empty")]
+    EmptyChild(u8),
 }
 
+/// Child slot holding nothing. The root is never a child, so its index is free to use.
+const VOID: u32 = 0;
+/// Set on child slots holding an index into `leaf_data` rather than `proxies`
+const LEAF: u32 = 1 << 31;
+/// Owner of a leaf stored in the root itself, when the tree spans a single voxel
+const ROOT_OWNER: u32 = u32::MAX;
+
+/// Sparse voxel octree keeping its nodes & values in flat arenas rather than boxing each branch.
+///
+/// Leaves are single voxels at integer positions. Children are indexed with x in bit 2, y in bit
+/// 1 & z in bit 0, so visiting them in index order walks the voxels in Morton order. The root
+/// grows in whichever direction is needed to fit inserted voxels.
+///
+/// Only branches get a proxy; the child slots of a branch hold `VOID`, a proxy index or a leaf
+/// index tagged with `LEAF`.
+#[derive(Debug, Clone)]
 pub struct Octree<T> {
+    /// Bounds of every voxel inserted so far; removals don't shrink it
     aabb: AABB,
+    /// Minimum corner of the root node
+    origin: Vector3<isize>,
+    /// The root node spans 2ᵈᵉᵖᵗʰ voxels along each axis
+    depth: u32,
     proxies: Vec<Proxy>,
+    /// Proxies freed by removals, reused before `proxies` grows
+    free_proxies: Vec<u32>,
     leaf_data: Vec<T>,
+    /// Slot of each value in `leaf_data`, as its branch's proxy index * 8 + child index
+    leaf_owners: Vec<u32>,
 }
 
 impl<T> Default for Octree<T> {
@@ -33,34 +61,373 @@ impl<T> Octree<T> {
     pub fn new() -> Self {
         Self {
             aabb: AABB::new_invalid(),
+            origin: Vector3::zeros(),
+            depth: 0,
             proxies: vec![Proxy {
                 parent: 0,
                 data: ProxyData::Void,
             }],
+            free_proxies: Vec::default(),
             leaf_data: Vec::default(),
+            leaf_owners: Vec::default(),
         }
     }
 
-    pub fn view(&self) -> View<&Self> {
+    pub fn view(&self) -> View<'_, &Self> {
         View::new(self, 0)
     }
 
-    pub fn view_mut(&mut self) -> View<&mut Self> {
+    pub fn view_mut(&mut self) -> View<'_, &mut Self> {
         View::new(self, 0)
     }
+
+    /// Number of voxels in the tree
+    pub fn len(&self) -> usize {
+        self.leaf_data.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.leaf_data.is_empty()
+    }
+
+    pub fn aabb(&self) -> &AABB {
+        &self.aabb
+    }
+
+    /// Minimum corner of the space covered by the root node
+    pub fn origin(&self) -> Vector3<isize> {
+        self.origin
+    }
+
+    /// Edge length of the space covered by the root node
+    pub fn size(&self) -> isize {
+        1 << self.depth
+    }
+
+    /// Bytes allocated for nodes & values
+    pub fn memory_usage(&self) -> usize {
+        self.proxies.capacity() * std::mem::size_of::<Proxy>()
+            + self.free_proxies.capacity() * std::mem::size_of::<u32>()
+            + self.leaf_data.capacity() * std::mem::size_of::<T>()
+            + self.leaf_owners.capacity() * std::mem::size_of::<u32>()
+    }
+
+    /// Whether `pos` is within the space covered by the root node
+    pub fn contains(&self, pos: Vector3<isize>) -> bool {
+        let size = self.size();
+        (0..3).all(|a| pos[a] >= self.origin[a] && pos[a] < self.origin[a] + size)
+    }
+
+    pub fn get(&self, pos: Vector3<isize>) -> Option<&T> {
+        self.find(pos).map(|lid| &self.leaf_data[lid as usize])
+    }
+
+    pub fn get_mut(&mut self, pos: Vector3<isize>) -> Option<&mut T> {
+        self.find(pos).map(|lid| &mut self.leaf_data[lid as usize])
+    }
+
+    /// First voxel in Morton order
+    pub fn first(&self) -> Option<(Vector3<isize>, &T)> {
+        self.iter().next()
+    }
+
+    /// Insert a voxel, returning the value it replaced
+    pub fn insert(&mut self, pos: Vector3<isize>, value: T) -> Option<T> {
+        self.grow_to(pos);
+        let corner = pos.map(|c| c as f32);
+        self.aabb.take_point(Point3::from(corner));
+        self.aabb.take_point(Point3::from(corner.add_scalar(1.)));
+
+        match self.proxies[0].data {
+            ProxyData::Leaf(lid) => {
+                return Some(std::mem::replace(&mut self.leaf_data[lid as usize], value))
+            }
+            ProxyData::Void if self.depth == 0 => {
+                self.proxies[0].data = ProxyData::Leaf(self.push_leaf(value, ROOT_OWNER));
+                return None;
+            }
+            ProxyData::Void => self.proxies[0].data = ProxyData::Branch([VOID; 8]),
+            ProxyData::Branch(_) => {}
+        }
+
+        let rel = pos - self.origin;
+        let mut node = 0;
+        for depth in (0..self.depth).rev() {
+            let index = child_index(rel, depth);
+            let child = self.children(node)[index];
+            if depth == 0 {
+                if child & LEAF != 0 {
+                    let lid = (child & !LEAF) as usize;
+                    return Some(std::mem::replace(&mut self.leaf_data[lid], value));
+                }
+                let lid = self.push_leaf(value, node << 3 | index as u32);
+                self.set_slot(node << 3 | index as u32, LEAF | lid);
+                return None;
+            }
+            node = match child {
+                VOID => {
+                    let id = self.alloc(Proxy {
+                        parent: node,
+                        data: ProxyData::Branch([VOID; 8]),
+                    });
+                    self.set_slot(node << 3 | index as u32, id);
+                    id
+                }
+                child => child,
+            };
+        }
+        unreachable!("leaves are always single voxels")
+    }
+
+    /// Remove a voxel, collapsing any branches it leaves empty
+    pub fn remove(&mut self, pos: Vector3<isize>) -> Option<T> {
+        let lid = self.find(pos)?;
+        let owner = self.leaf_owners[lid as usize];
+        let value = self.leaf_data.swap_remove(lid as usize);
+        self.leaf_owners.swap_remove(lid as usize);
+        if let Some(&moved) = self.leaf_owners.get(lid as usize) {
+            self.set_slot(moved, LEAF | lid);
+        }
+        self.set_slot(owner, VOID);
+        if owner == ROOT_OWNER {
+            return Some(value);
+        }
+
+        let mut node = owner >> 3;
+        while self.children(node).iter().all(|c| *c == VOID) {
+            if node == 0 {
+                self.proxies[0].data = ProxyData::Void;
+                break;
+            }
+            let parent = self.proxies[node as usize].parent;
+            let index = self
+                .children(parent)
+                .iter()
+                .position(|c| *c == node)
+                .unwrap();
+            self.set_slot(parent << 3 | index as u32, VOID);
+            self.free_proxies.push(node);
+            node = parent;
+        }
+        Some(value)
+    }
+
+    /// Iterate over voxels in Morton order
+    pub fn iter(&self) -> Iter<'_, T> {
+        Iter {
+            leaves: self.leaves(),
+        }
+    }
+
+    /// Remove every voxel in Morton order, leaving the tree empty
+    pub fn drain(&mut self) -> std::vec::IntoIter<(Vector3<isize>, T)> {
+        let tree = std::mem::take(self);
+        let order = tree.leaves().collect::<Vec<_>>();
+        let mut data = tree.leaf_data.into_iter().map(Some).collect::<Vec<_>>();
+        order
+            .into_iter()
+            .map(|(pos, lid)| (pos, data[lid as usize].take().unwrap()))
+            .collect::<Vec<_>>()
+            .into_iter()
+    }
+
+    fn leaves(&self) -> Leaves<'_, T> {
+        Leaves {
+            tree: self,
+            stack: match self.proxies[0].data {
+                ProxyData::Void => vec![],
+                ProxyData::Leaf(lid) => vec![(LEAF | lid, self.origin, 0)],
+                ProxyData::Branch(_) => vec![(0, self.origin, self.depth)],
+            },
+        }
+    }
+
+    /// Leaf index of the voxel at `pos`
+    fn find(&self, pos: Vector3<isize>) -> Option<u32> {
+        if !self.contains(pos) {
+            return None;
+        }
+        if let ProxyData::Leaf(lid) = self.proxies[0].data {
+            return Some(lid);
+        }
+        let rel = pos - self.origin;
+        let mut node = 0;
+        for depth in (0..self.depth).rev() {
+            let children = match &self.proxies[node as usize].data {
+                ProxyData::Branch(children) => children,
+                _ => return None,
+            };
+            match children[child_index(rel, depth)] {
+                VOID => return None,
+                child if child & LEAF != 0 => return Some(child & !LEAF),
+                child => node = child,
+            }
+        }
+        None
+    }
+
+    fn children(&self, node: u32) -> [u32; 8] {
+        match self.proxies[node as usize].data {
+            ProxyData::Branch(children) => children,
+            _ => [VOID; 8],
+        }
+    }
+
+    /// Point a child slot, given as proxy index * 8 + child index, at `child`
+    fn set_slot(&mut self, slot: u32, child: u32) {
+        if slot == ROOT_OWNER {
+            self.proxies[0].data = match child {
+                VOID => ProxyData::Void,
+                child => ProxyData::Leaf(child & !LEAF),
+            };
+            return;
+        }
+        match &mut self.proxies[(slot >> 3) as usize].data {
+            ProxyData::Branch(children) => children[(slot & 7) as usize] = child,
+            _ => unreachable!("slots belong to branches"),
+        }
+        if child != VOID && child & LEAF == 0 {
+            self.proxies[child as usize].parent = slot >> 3;
+        }
+    }
+
+    fn push_leaf(&mut self, value: T, owner: u32) -> u32 {
+        self.leaf_data.push(value);
+        self.leaf_owners.push(owner);
+        (self.leaf_data.len() - 1) as u32
+    }
+
+    fn alloc(&mut self, proxy: Proxy) -> u32 {
+        match self.free_proxies.pop() {
+            Some(id) => {
+                self.proxies[id as usize] = proxy;
+                id
+            }
+            None => {
+                self.proxies.push(proxy);
+                (self.proxies.len() - 1) as u32
+            }
+        }
+    }
+
+    /// Double the root until it contains `pos`
+    fn grow_to(&mut self, pos: Vector3<isize>) {
+        if self.is_empty() {
+            *self = Self {
+                aabb: self.aabb,
+                origin: pos,
+                ..Self::new()
+            };
+            return;
+        }
+        while !self.contains(pos) {
+            // The old root becomes the child on the side facing away from `pos`
+            let offset = pos.zip_map(&self.origin, |p, o| (p < o) as isize);
+            self.origin -= offset * self.size();
+            let index = (offset.x << 2 | offset.y << 1 | offset.z) as u32;
+
+            let old = match self.proxies[0].data {
+                ProxyData::Leaf(lid) => LEAF | lid,
+                data => self.alloc(Proxy { parent: 0, data }),
+            };
+            self.proxies[0].data = ProxyData::Branch([VOID; 8]);
+            self.set_slot(index, old);
+            if old & LEAF != 0 {
+                self.leaf_owners[(old & !LEAF) as usize] = index;
+            } else {
+                for (i, child) in self.children(old).into_iter().enumerate() {
+                    match child {
+                        VOID => {}
+                        child if child & LEAF != 0 => {
+                            self.leaf_owners[(child & !LEAF) as usize] = old << 3 | i as u32
+                        }
+                        child => self.proxies[child as usize].parent = old,
+                    }
+                }
+            }
+            self.depth += 1;
+        }
+    }
 }
 
+impl<'tree, T> IntoIterator for &'tree Octree<T> {
+    type Item = (Vector3<isize>, &'tree T);
+    type IntoIter = Iter<'tree, T>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.iter()
+    }
+}
+
+/// Offset, in units of the child's size, of the child at `index`
+pub fn child_offset(index: usize) -> Vector3<isize> {
+    Vector3::new(
+        (index >> 2 & 1) as isize,
+        (index >> 1 & 1) as isize,
+        (index & 1) as isize,
+    )
+}
+
+/// Index of the child containing `rel`, a position relative to the root's origin, among
+/// children spanning 2ᵈᵉᵖᵗʰ voxels
+fn child_index(rel: Vector3<isize>, depth: u32) -> usize {
+    ((rel.x >> depth & 1) << 2 | (rel.y >> depth & 1) << 1 | (rel.z >> depth & 1)) as usize
+}
+
+/// Depth-first walk over leaves, yielding their positions & indices into `leaf_data`
+struct Leaves<'tree, T> {
+    tree: &'tree Octree<T>,
+    stack: Vec<(u32, Vector3<isize>, u32)>,
+}
+
+impl<'tree, T> Iterator for Leaves<'tree, T> {
+    type Item = (Vector3<isize>, u32);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while let Some((node, origin, depth)) = self.stack.pop() {
+            if node & LEAF != 0 {
+                return Some((origin, node & !LEAF));
+            }
+            let half = 1 << (depth - 1);
+            for (i, child) in self.tree.children(node).into_iter().enumerate().rev() {
+                if child != VOID {
+                    self.stack
+                        .push((child, origin + child_offset(i) * half, depth - 1));
+                }
+            }
+        }
+        None
+    }
+}
+
+pub struct Iter<'tree, T> {
+    leaves: Leaves<'tree, T>,
+}
+
+impl<'tree, T> Iterator for Iter<'tree, T> {
+    type Item = (Vector3<isize>, &'tree T);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let (pos, lid) = self.leaves.next()?;
+        Some((pos, &self.leaves.tree.leaf_data[lid as usize]))
+    }
+}
+
+#[derive(Debug, Copy, Clone)]
 pub struct Proxy {
     parent: u32,
     data: ProxyData,
 }
 
+#[derive(Debug, Copy, Clone)]
 pub enum ProxyData {
     Void,
     Leaf(u32),
     Branch([u32; 8]),
 }
 
+/// Cursor over the nodes of an octree. `target` is the root (0), a branch's proxy index, or a
+/// leaf index tagged with `LEAF`.
 #[derive(Debug, Copy, Clone)]
 pub struct View<'tree, Tree: 'tree> {
     tree: Tree,
@@ -73,74 +440,229 @@ impl<'tree, T: 'tree, Tree: Deref<Target = Octree<T>> + 'tree> View<'tree, Tree>
         Self {
             tree,
             target,
-            _lifetime: PhantomData::default(),
+            _lifetime: PhantomData,
+        }
+    }
+
+    /// Proxy of the targeted node, unless it's a leaf below the root
+    pub fn proxy(&self) -> Option<&Proxy> {
+        match self.target & LEAF {
+            0 => Some(&self.tree.proxies[self.target as usize]),
+            _ => None,
         }
     }
 
-    pub fn proxy(&self) -> &Proxy {
-        &self.tree.proxies[self.target as usize]
+    fn parent_target(&self) -> Result<u32, Error> {
+        if self.target & LEAF != 0 {
+            return Ok(self.tree.leaf_owners[(self.target & !LEAF) as usize] >> 3);
+        }
+        match self.target {
+            0 => Err(Error::ParentOfRoot),
+            node => Ok(self.tree.proxies[node as usize].parent),
+        }
+    }
+
+    fn child_target(&self, index: u8) -> Result<u32, Error> {
+        match self.proxy().map(|p| p.data) {
+            Some(ProxyData::Branch(c)) => {
+                match *c.get(index as usize).ok_or(Error::OutOfRange(index))? {
+                    VOID => Err(Error::EmptyChild(index)),
+                    child => Ok(child),
+                }
+            }
+            _ => Err(Error::NoChildren),
+        }
     }
 
     pub fn parent(&self) -> Result<Self, Error>
     where
         Tree: Copy,
     {
-        Ok(Self::new(self.tree, {
-            let pid = self.proxy().parent;
-            if pid == self.target {
-                return Err(Error::ParentOfRoot);
-            }
-            pid
-        }))
+        Ok(Self::new(self.tree, self.parent_target()?))
     }
 
     pub fn child(&self, index: u8) -> Result<Self, Error>
     where
         Tree: Copy,
     {
-        Ok(Self::new(
-            self.tree,
-            match self.proxy().data {
-                ProxyData::Branch(c) => *c.get(index as usize).ok_or(Error::OutOfRange(index))?,
-                _ => return Err(Error::NoChildren),
-            },
-        ))
+        Ok(Self::new(self.tree, self.child_target(index)?))
     }
 
+    /// Views of the non-empty children, in index order
     pub fn children(&self) -> Box<dyn Iterator<Item = Self> + '_>
     where
         Tree: Copy,
     {
-        match self.proxy().data {
-            ProxyData::Branch(c) => {
-                Box::new(c.into_iter().map(|target| Self::new(self.tree, target)))
-            }
+        match self.proxy().map(|p| p.data) {
+            Some(ProxyData::Branch(c)) => Box::new(
+                c.into_iter()
+                    .filter(|target| *target != VOID)
+                    .map(|target| Self::new(self.tree, target)),
+            ),
             _ => Box::new([].into_iter()),
         }
     }
+
     pub fn data(&self) -> Option<&T> {
-        match self.proxy().data {
-            ProxyData::Leaf(lid) => Some(&self.tree.leaf_data[lid as usize]),
-            _ => None,
+        match (self.target & LEAF, self.proxy().map(|p| p.data)) {
+            (0, Some(ProxyData::Leaf(lid))) => Some(&self.tree.leaf_data[lid as usize]),
+            (0, _) => None,
+            _ => Some(&self.tree.leaf_data[(self.target & !LEAF) as usize]),
         }
     }
 }
 
 impl<'tree, T: 'tree, Tree: DerefMut<Target = Octree<T>> + 'tree> View<'tree, Tree> {
     pub fn parent_mut(&mut self) -> Result<&mut Self, Error> {
-        let pid = self.proxy().parent;
-        if pid == self.target {
-            return Err(Error::ParentOfRoot);
-        }
-        self.target = pid;
+        self.target = self.parent_target()?;
         Ok(self)
     }
 
     pub fn child_mut(&mut self, index: u8) -> Result<&mut Self, Error> {
-        self.target = match self.proxy().data {
-            ProxyData::Branch(c) => *c.get(index as usize).ok_or(Error::OutOfRange(index))?,
-            _ => return Err(Error::NoChildren),
-        };
+        self.target = self.child_target(index)?;
         Ok(self)
     }
+
+    pub fn data_mut(&mut self) -> Option<&mut T> {
+        let lid = match (self.target & LEAF, self.proxy().map(|p| p.data)) {
+            (0, Some(ProxyData::Leaf(lid))) => lid,
+            (0, _) => return None,
+            _ => self.target & !LEAF,
+        };
+        Some(&mut self.tree.leaf_data[lid as usize])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Interleaves the bits of a position relative to the root, x highest, like child indices
+    fn morton(rel: Vector3<isize>, depth: u32) -> usize {
+        (0..depth)
+            .rev()
+            .fold(0, |key, d| key << 3 | child_index(rel, d))
+    }
+
+    fn cube(size: isize) -> Vec<Vector3<isize>> {
+        let mut res = vec![];
+        for x in 0..size {
+            for y in 0..size {
+                for z in 0..size {
+                    res.push(Vector3::new(x, y, z));
+                }
+            }
+        }
+        res
+    }
+
+    #[test]
+    fn insert_and_get() {
+        let mut tree = Octree::new();
+        assert_eq!(tree.insert(Vector3::new(3, 1, 4), 'a'), None);
+        assert_eq!(tree.insert(Vector3::new(1, 5, 9), 'b'), None);
+        assert_eq!(tree.insert(Vector3::new(3, 1, 4), 'c'), Some('a'));
+        assert_eq!(tree.len(), 2);
+        assert_eq!(tree.get(Vector3::new(3, 1, 4)), Some(&'c'));
+        assert_eq!(tree.get(Vector3::new(1, 5, 9)), Some(&'b'));
+        assert_eq!(tree.get(Vector3::new(2, 6, 5)), None);
+        assert_eq!(tree.get(Vector3::new(-100, 0, 0)), None);
+    }
+
+    #[test]
+    fn grows_towards_negative_positions() {
+        let mut tree = Octree::new();
+        let positions = [
+            Vector3::new(0, 0, 0),
+            Vector3::new(-1, 0, 0),
+            Vector3::new(5, -7, 2),
+            Vector3::new(-20, 13, -3),
+        ];
+        for (i, pos) in positions.into_iter().enumerate() {
+            tree.insert(pos, i);
+        }
+        for (i, pos) in positions.into_iter().enumerate() {
+            assert!(tree.contains(pos));
+            assert_eq!(tree.get(pos), Some(&i));
+        }
+        assert_eq!(tree.len(), positions.len());
+    }
+
+    #[test]
+    fn remove_collapses_empty_branches() {
+        let mut tree = Octree::new();
+        let positions = cube(4);
+        for (i, pos) in positions.iter().enumerate() {
+            tree.insert(*pos, i);
+        }
+        tree.insert(Vector3::new(-9, 2, 30), positions.len());
+
+        for (i, pos) in positions.iter().enumerate().rev() {
+            assert_eq!(tree.remove(*pos), Some(i));
+            assert_eq!(tree.remove(*pos), None);
+        }
+        assert_eq!(tree.len(), 1);
+        assert_eq!(tree.get(Vector3::new(-9, 2, 30)), Some(&positions.len()));
+
+        assert_eq!(tree.remove(Vector3::new(-9, 2, 30)), Some(positions.len()));
+        assert!(tree.is_empty());
+        assert!(matches!(tree.proxies[0].data, ProxyData::Void));
+        // every branch but the root went back to the free list
+        assert_eq!(tree.free_proxies.len(), tree.proxies.len() - 1);
+        assert_eq!(tree.iter().count(), 0);
+    }
+
+    #[test]
+    fn single_voxel_root() {
+        let mut tree = Octree::new();
+        tree.insert(Vector3::new(7, -2, 3), 1);
+        assert!(matches!(tree.proxies[0].data, ProxyData::Leaf(_)));
+        assert_eq!(tree.first(), Some((Vector3::new(7, -2, 3), &1)));
+        assert_eq!(tree.remove(Vector3::new(7, -2, 3)), Some(1));
+        assert!(matches!(tree.proxies[0].data, ProxyData::Void));
+    }
+
+    #[test]
+    fn iterates_in_morton_order() {
+        let mut tree = Octree::new();
+        // insert out of order so that leaf storage order differs from Morton order
+        let mut positions = cube(4);
+        positions.reverse();
+        positions.rotate_left(17);
+        positions.push(Vector3::new(-3, 6, -1));
+        for pos in &positions {
+            tree.insert(*pos, *pos);
+        }
+
+        let depth = tree.size().trailing_zeros();
+        let keys = tree
+            .iter()
+            .map(|(pos, value)| {
+                assert_eq!(pos, *value);
+                morton(pos - tree.origin(), depth)
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(keys.len(), positions.len());
+        assert!(keys.windows(2).all(|w| w[0] < w[1]));
+        assert_eq!(
+            tree.first().map(|(pos, _)| pos),
+            tree.iter().next().map(|(pos, _)| pos)
+        );
+    }
+
+    #[test]
+    fn drain_matches_iter_and_empties() {
+        let mut tree = Octree::new();
+        for (i, pos) in cube(3).into_iter().rev().enumerate() {
+            tree.insert(pos - Vector3::repeat(1), i);
+        }
+        let expected = tree
+            .iter()
+            .map(|(pos, value)| (pos, *value))
+            .collect::<Vec<_>>();
+        let drained = tree.drain().collect::<Vec<_>>();
+        assert_eq!(drained, expected);
+        assert!(tree.is_empty());
+        assert_eq!(tree.iter().count(), 0);
+    }
 }
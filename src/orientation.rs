@@ -0,0 +1,187 @@
+use std::collections::HashMap;
+
+use brickadia::save::{self as brs, Direction, Rotation};
+use nalgebra::Vector3;
+
+use crate::store::VoxelStore;
+use crate::voxelize::Voxel;
+use crate::BrickType;
+
+pub const DIRECTIONS: [Direction; 6] = [
+    Direction::ZPositive,
+    Direction::ZNegative,
+    Direction::XPositive,
+    Direction::XNegative,
+    Direction::YPositive,
+    Direction::YNegative,
+];
+
+pub const ROTATIONS: [Rotation; 4] = [
+    Rotation::Deg0,
+    Rotation::Deg90,
+    Rotation::Deg180,
+    Rotation::Deg270,
+];
+
+/// Map a vector from a brick's local space into save space. Bricks are first rotated about their
+/// local z axis, then their z axis is pointed along `direction`.
+pub fn orient(v: Vector3<i32>, direction: Direction, rotation: Rotation) -> Vector3<i32> {
+    let v = match rotation {
+        Rotation::Deg0 => v,
+        Rotation::Deg90 => Vector3::new(-v.y, v.x, v.z),
+        Rotation::Deg180 => Vector3::new(-v.x, -v.y, v.z),
+        Rotation::Deg270 => Vector3::new(v.y, -v.x, v.z),
+    };
+    match direction {
+        Direction::ZPositive => v,
+        Direction::ZNegative => Vector3::new(-v.x, v.y, -v.z),
+        Direction::XPositive => Vector3::new(v.z, v.y, -v.x),
+        Direction::XNegative => Vector3::new(-v.z, v.y, v.x),
+        Direction::YPositive => Vector3::new(v.x, v.z, -v.y),
+        Direction::YNegative => Vector3::new(v.x, -v.z, v.y),
+    }
+}
+
+/// Extents of a procedural brick along the x, y & z axes of the save. Its size is given along its
+/// own axes, before it's oriented.
+pub fn world_size(brick: &brs::Brick) -> (u32, u32, u32) {
+    let local = match brick.size {
+        brs::Size::Procedural(w, l, h) => [w, l, h],
+        _ => [0; 3],
+    };
+    let mut world = [0; 3];
+    for (axis, size) in local.into_iter().enumerate() {
+        world[orient(Vector3::ith(axis, 1), brick.direction, brick.rotation).iamax()] = size;
+    }
+    (world[0], world[1], world[2])
+}
+
+/// Find an orientation that maps the local vector `from` onto `to`, if there is one
+pub fn find_orientation(from: Vector3<i32>, to: Vector3<i32>) -> Option<(Direction, Rotation)> {
+    DIRECTIONS
+        .iter()
+        .flat_map(|d| ROTATIONS.iter().map(move |r| (*d, *r)))
+        .find(|(d, r)| orient(from, *d, *r) == to)
+}
+
+/// Round each component of a normal to -1, 0 or 1, keeping only components at least
+/// `threshold` in magnitude
+pub fn normal_signs(normal: &Vector3<f32>, threshold: f32) -> Vector3<i32> {
+    normal.map(|c| match c {
+        c if c >= threshold => 1,
+        c if c <= -threshold => -1,
+        _ => 0,
+    })
+}
+
+/// Normal of the sloped face of ramps & wedges placed with `Direction::ZPositive` &
+/// `Rotation::Deg0`. They're taken to slope along their own x axis like the microwedges in
+/// [`smooth`](crate::smooth), which haven't been checked in game either.
+const RAMP_SLOPE: [i32; 3] = [1, 0, 1];
+
+/// Turn a ramp or wedge so its slope faces along `normal`, in save axes, keeping it upright on
+/// floors & upside down on ceilings. Its size is permuted to cover the same space. Normals
+/// without a horizontal part leave the brick as it is. Returns whether the brick was turned.
+pub fn orient_slope(brick: &mut brs::Brick, normal: Vector3<f32>) -> bool {
+    if !matches!(brick.size, brs::Size::Procedural(..)) {
+        return false;
+    }
+    let across = match normal.x.abs() >= normal.y.abs() {
+        true => 0,
+        false => 1,
+    };
+    if normal[across] == 0. {
+        return false;
+    }
+    let mut target = Vector3::zeros();
+    target[across] = normal[across].signum() as i32;
+    target.z = match normal.z < 0. {
+        true => -1,
+        false => 1,
+    };
+    let slope = Vector3::from(RAMP_SLOPE);
+    let (direction, rotation) = match [Direction::ZPositive, Direction::ZNegative]
+        .iter()
+        .flat_map(|d| ROTATIONS.iter().map(move |r| (*d, *r)))
+        .find(|(d, r)| orient(slope, *d, *r) == target)
+    {
+        Some(orientation) => orientation,
+        None => return false,
+    };
+    if direction == brick.direction && rotation == brick.rotation {
+        return false;
+    }
+
+    let (w, l, h) = world_size(brick);
+    let world = [w, l, h];
+    let local = [Vector3::x(), Vector3::y(), Vector3::z()]
+        .map(|v| world[orient(v, direction, rotation).iamax()]);
+    brick.size = brs::Size::Procedural(local[0], local[1], local[2]);
+    brick.direction = direction;
+    brick.rotation = rotation;
+    true
+}
+
+/// Record the normal of every voxel in the octree
+pub fn collect_normals(octree: &dyn VoxelStore<Voxel>) -> HashMap<Vector3<isize>, Vector3<f32>> {
+    octree
+        .iter()
+        .map(|(pos, voxel)| (pos, voxel.normal))
+        .collect()
+}
+
+/// Turn the top of each brick in `save_data.bricks[start..]` towards the summed normal of the
+/// voxels it covers, so tiles show their smooth side & studs face out of walls and overhangs.
+/// Sizes are permuted to match, so each brick keeps covering the same voxels.
+pub fn orient_to_normals(
+    save_data: &mut brs::SaveData,
+    start: usize,
+    bricktype: BrickType,
+    normals: &HashMap<Vector3<isize>, Vector3<f32>>,
+) {
+    let (sx, sy, sz) = bricktype.voxel_size();
+    let mut oriented = 0;
+    for brick in &mut save_data.bricks[start..] {
+        let (w, l, h) = match brick.size {
+            brs::Size::Procedural(w, l, h) => (w as isize, l as isize, h as isize),
+            _ => continue,
+        };
+        let (x, y, z) = (
+            (brick.position.0 as isize - w) / (2 * sx),
+            (brick.position.1 as isize - l) / (2 * sy),
+            (brick.position.2 as isize - h) / (2 * sz),
+        );
+
+        let mut normal = Vector3::<f32>::zeros();
+        for bx in x..x + w / sx {
+            for by in y..y + l / sy {
+                for bz in z..z + h / sz {
+                    // Coordinates are rotated
+                    if let Some(n) = normals.get(&Vector3::new(bx, bz, by)) {
+                        normal += Vector3::new(n.x, n.z, n.y);
+                    }
+                }
+            }
+        }
+
+        let axis = normal.iamax();
+        if normal[axis] == 0. {
+            continue;
+        }
+        let mut up = Vector3::zeros();
+        up[axis] = normal[axis].signum() as i32;
+        let (direction, rotation) = match find_orientation(Vector3::z(), up) {
+            Some(orientation) => orientation,
+            None => continue,
+        };
+
+        let world = [w as u32, l as u32, h as u32];
+        let local = [Vector3::x(), Vector3::y(), Vector3::z()]
+            .map(|v| world[orient(v, direction, rotation).iamax()]);
+        brick.size = brs::Size::Procedural(local[0], local[1], local[2]);
+        brick.direction = direction;
+        brick.rotation = rotation;
+        oriented += (up != Vector3::z()) as usize;
+    }
+    tracing::info!("Reoriented {oriented} bricks");
+}
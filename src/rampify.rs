@@ -1,8 +1,214 @@
+use crate::color::{oklab_distance, rgb2oklab};
+use crate::orientation::{orient_slope, world_size};
+use crate::{asset_index, BrickType};
 use brickadia::save::*;
+use nalgebra::{Vector3, Vector4};
 use rampifier::{Rampifier, RampifierConfig};
+use std::collections::HashMap;
 use std::time::Instant;
 
-pub fn rampify(save: &mut SaveData) {
+/// Hashable stand-in for a `BrickColor`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+enum ColorKey {
+    Index(u32),
+    Unique([u8; 4]),
+}
+
+impl ColorKey {
+    fn new(color: &BrickColor) -> Self {
+        match color {
+            BrickColor::Index(i) => Self::Index(*i),
+            BrickColor::Unique(c) => Self::Unique([c.r, c.g, c.b, c.a]),
+        }
+    }
+
+    fn brick_color(self) -> BrickColor {
+        match self {
+            Self::Index(i) => BrickColor::Index(i),
+            Self::Unique([r, g, b, a]) => BrickColor::Unique(Color { r, g, b, a }),
+        }
+    }
+
+    fn rgba(self, colorset: &[Color]) -> Vector4<u8> {
+        match self {
+            Self::Index(i) => {
+                let c = &colorset[i as usize];
+                Vector4::new(c.r, c.g, c.b, c.a)
+            }
+            Self::Unique([r, g, b, a]) => Vector4::new(r, g, b, a),
+        }
+    }
+}
+
+/// The rampifier works on `u8` voxel values, so brick colors are stored in a table of at most 256
+/// entries. If there are more distinct colors than that, the least common ones are replaced by
+/// their closest (in OKLab) remaining color.
+fn build_color_table(save: &SaveData) -> (Vec<ColorKey>, HashMap<ColorKey, u8>) {
+    let mut counts = HashMap::<ColorKey, usize>::new();
+    for brick in &save.bricks {
+        if let Size::Procedural(..) = brick.size {
+            *counts.entry(ColorKey::new(&brick.color)).or_default() += 1;
+        }
+    }
+
+    let mut colors = counts.into_iter().collect::<Vec<_>>();
+    colors.sort_by(|(ak, ac), (bk, bc)| bc.cmp(ac).then(ak.cmp(bk)));
+    let colors = colors.into_iter().map(|(k, _)| k).collect::<Vec<_>>();
+
+    let table_len = colors.len().min(u8::MAX as usize + 1);
+    if colors.len() > table_len {
+        tracing::warn!(
+            "Rampify supports at most {table_len} colors, merging {} colors into similar ones",
+            colors.len() - table_len
+        );
+    }
+    let table = colors[..table_len].to_vec();
+    let table_oklab = table
+        .iter()
+        .map(|k| rgb2oklab(k.rgba(&save.header2.colors)))
+        .collect::<Vec<_>>();
+
+    let indices = colors
+        .iter()
+        .enumerate()
+        .map(|(i, k)| {
+            if i < table_len {
+                return (*k, i as u8);
+            }
+            let oklab = rgb2oklab(k.rgba(&save.header2.colors));
+            let nearest = table_oklab
+                .iter()
+                .enumerate()
+                .min_by(|(_, a), (_, b)| {
+                    oklab_distance(a, &oklab).total_cmp(&oklab_distance(b, &oklab))
+                })
+                .map(|(i, _)| i)
+                .unwrap_or(0);
+            (*k, nearest as u8)
+        })
+        .collect();
+
+    (table, indices)
+}
+
+/// Rescale a brick generated by the rampifier, which works in plates, to voxels of `voxel_size`
+fn rescale(brick: &mut Brick, voxel_size: (isize, isize, isize)) {
+    let (sx, sy, sz) = (
+        voxel_size.0 as i32,
+        voxel_size.1 as i32,
+        voxel_size.2 as i32,
+    );
+    let (x, y, z) = brick.position;
+    brick.position = (x / 5 * sx, y / 5 * sy, z / 2 * sz);
+    if let Size::Procedural(w, l, h) = brick.size {
+        brick.size = Size::Procedural(w / 5 * sx as u32, l / 5 * sy as u32, h / 2 * sz as u32);
+    }
+}
+
+/// Which ramps [`rampify`] keeps, and how the space left around them is filled
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RampOptions {
+    /// Place ramps on upward-facing slopes
+    pub floor_ramps: bool,
+    /// Place ramps on downward-facing slopes
+    pub ceiling_ramps: bool,
+    /// Shortest run, in voxels along the slope, of the ramps kept
+    pub min_length: u32,
+    /// Longest run, in voxels along the slope, of the ramps kept
+    pub max_length: Option<u32>,
+    /// Keep the wedges the rampifier places alongside ramps
+    pub wedges: bool,
+    /// Largest size, in voxels along each axis, of the bricks filling the rest
+    pub max_fill: u32,
+}
+
+/// Grid coordinates of the voxels within a ramp's bounds, with the ramp still in the
+/// rampifier's plate units
+fn ramp_cells(ramp: &Brick) -> impl Iterator<Item = (i32, i32, i32)> {
+    let (x, y, z) = ramp.position;
+    let (w, l, h) = world_size(ramp);
+    let (w, l, h) = (w as i32, l as i32, h as i32);
+    let min = ((x - w) / 10, (y - l) / 10, (z - h) / 4);
+    let (w, l, h) = (w / 5, l / 5, h / 2);
+    (min.2..min.2 + h).flat_map(move |k| {
+        (min.1..min.1 + l).flat_map(move |j| (min.0..min.0 + w).map(move |i| (i, j, k)))
+    })
+}
+
+/// Grid indices of the voxels within a ramp's bounds, with the ramp still in the rampifier's
+/// plate units
+fn ramp_voxels(ramp: &Brick, grid_size: (usize, usize, usize)) -> impl Iterator<Item = usize> {
+    ramp_cells(ramp).filter_map(move |(i, j, k)| {
+        let in_grid = [(i, grid_size.0), (j, grid_size.1), (k, grid_size.2)]
+            .iter()
+            .all(|&(c, size)| c >= 0 && (c as usize) < size);
+        in_grid
+            .then(|| i as usize + j as usize * grid_size.0 + k as usize * grid_size.0 * grid_size.1)
+    })
+}
+
+/// Split a ramp, still in the rampifier's plate units, into a lip of `brick_index` one voxel tall
+/// along its base & a wedge of `wedge_index` sloping over the rest. Microwedges can't take the shape
+/// of a ramp, so ramps on microbricks are placed this way. Ramps one voxel tall are all slope.
+fn split_ramp(ramp: Brick, brick_index: u32, wedge_index: u32) -> Vec<Brick> {
+    // the base is at the bottom of the ramp's local z axis, which ceiling ramps turn upside down
+    let base = match ramp.direction {
+        Direction::ZPositive => -1,
+        Direction::ZNegative => 1,
+        _ => 0,
+    };
+    let (w, l, h) = match ramp.size {
+        Size::Procedural(w, l, h) if h > 2 && base != 0 => (w, l, h),
+        _ => {
+            return vec![Brick {
+                asset_name_index: wedge_index,
+                ..ramp
+            }]
+        }
+    };
+    let (x, y, z) = ramp.position;
+    let lip = Brick {
+        asset_name_index: brick_index,
+        size: Size::Procedural(w, l, 2),
+        position: (x, y, z + base * (h as i32 - 2)),
+        ..ramp.clone()
+    };
+    let wedge = Brick {
+        asset_name_index: wedge_index,
+        size: Size::Procedural(w, l, h - 2),
+        position: (x, y, z - base * 2),
+        ..ramp
+    };
+    vec![lip, wedge]
+}
+
+/// Run of a ramp along its slope, in voxels, with the ramp still in the rampifier's plate units.
+/// Ramps slope along their own x axis, whichever way they're rotated.
+fn ramp_length(ramp: &Brick) -> u32 {
+    match ramp.size {
+        Size::Procedural(w, _, _) => w / 5,
+        _ => 0,
+    }
+}
+
+/// Generate ramps over the bricks in `save`, then fill what's left with boxes of at most
+/// `options.max_fill` voxels along each axis. Bricks are read & written on the voxel grid of
+/// `bricktype`; on microbricks, wedges become microwedges & ramps are split by [`split_ramp`].
+/// With the `normals` of the voxels the bricks were made from, each ramp & wedge is turned so
+/// its slope faces their sum over the voxels it covers.
+pub fn rampify(
+    save: &mut SaveData,
+    bricktype: BrickType,
+    options: RampOptions,
+    normals: Option<&HashMap<Vector3<isize>, Vector3<f32>>>,
+) {
+    let voxel_size = bricktype.voxel_size();
+    let (sx, sy, sz) = (
+        voxel_size.0 as i32,
+        voxel_size.1 as i32,
+        voxel_size.2 as i32,
+    );
+
     // Read colors from sample save.
 
     /////////////////////////////////////////////////////////////
@@ -16,9 +222,9 @@ pub fn rampify(save: &mut SaveData) {
             y -= l_half as i32;
             z -= h_half as i32;
 
-            x /= 10;
-            y /= 10;
-            z /= 4;
+            x /= 2 * sx;
+            y /= 2 * sy;
+            z /= 2 * sz;
 
             return (x, y, z);
         }
@@ -26,7 +232,7 @@ pub fn rampify(save: &mut SaveData) {
         (0, 0, 0)
     };
 
-    println!("Converting .brs into voxels...");
+    tracing::info!("Converting .brs into voxels...");
 
     let now = Instant::now();
 
@@ -36,9 +242,9 @@ pub fn rampify(save: &mut SaveData) {
 
     for brick in &save.bricks {
         if let Size::Procedural(w_half, l_half, h_half) = brick.size {
-            let w = w_half as i32 / 5;
-            let l = l_half as i32 / 5;
-            let h = h_half as i32 / 2;
+            let w = w_half as i32 / sx;
+            let l = l_half as i32 / sy;
+            let h = h_half as i32 / sz;
 
             let pos = fix_brick_pos(&brick);
 
@@ -64,6 +270,8 @@ pub fn rampify(save: &mut SaveData) {
         pos.0 + pos.1 * grid_size.0 + pos.2 * grid_size.0 * grid_size.1
     };
 
+    let (color_table, color_indices) = build_color_table(save);
+
     let mut grid: Vec<Option<u8>> = vec![None; grid_size.0 * grid_size.1 * grid_size.2];
 
     for brick in &save.bricks {
@@ -75,28 +283,23 @@ pub fn rampify(save: &mut SaveData) {
                 (pos.2 - min_bounds.2) as usize,
             );
 
-            let w = w_half as usize / 5;
-            let l = l_half as usize / 5;
-            let h = h_half as usize / 2;
+            let w = (w_half as i32 / sx) as usize;
+            let l = (l_half as i32 / sy) as usize;
+            let h = (h_half as i32 / sz) as usize;
+            let value = color_indices[&ColorKey::new(&brick.color)];
 
             for i in 0..w {
                 for j in 0..l {
                     for k in 0..h {
                         let pos = (pos.0 + i, pos.1 + j, pos.2 + k);
-
-                        if let BrickColor::Index(index) = brick.color {
-                            grid[get_index(pos)] = Some(index as u8);
-                        }
+                        grid[get_index(pos)] = Some(value);
                     }
                 }
             }
         }
     }
 
-    println!(
-        " - Done in {}s\n",
-        now.elapsed().as_millis() as f64 / 1000.0
-    );
+    tracing::info!("Done in {}s", now.elapsed().as_millis() as f64 / 1000.0);
 
     let box_remove =
         |g: &mut Vec<Option<u8>>, pos: &(usize, usize, usize), size: &(usize, usize, usize)| {
@@ -151,38 +354,64 @@ pub fn rampify(save: &mut SaveData) {
 
     save.bricks.clear();
 
-    println!("Generating ramps...");
+    tracing::info!("Generating ramps...");
 
     let vox_count = grid.len();
 
-    let mut rampifier = Rampifier::new(
-        grid_size,
-        grid,
-        RampifierConfig {
+    let config = match bricktype {
+        // ramps are split into microwedges & microbricks once generated
+        BrickType::Microbricks => RampifierConfig {
+            brick_index: 0,
+            ramp_index: asset_index(save, "PB_DefaultRamp"),
+            wedge_index: asset_index(save, "PB_DefaultMicroWedge"),
+            ..RampifierConfig::default()
+        },
+        BrickType::Default | BrickType::Tiles => RampifierConfig {
             brick_index: 1,
             ramp_index: 2,
             wedge_index: 3,
             ..RampifierConfig::default()
         },
-    );
+    };
+    let brick_index = config.brick_index;
+    let ramp_index = config.ramp_index;
+    let wedge_index = config.wedge_index;
+    // kept to give the voxels of dropped ramps back to the gap filler
+    let solid = grid.clone();
+    let mut rampifier = Rampifier::new(grid_size, grid, config);
 
     let now = Instant::now();
 
     // Generate ramps for floor and ceiling.
-    let ramps = &mut rampifier.generate_ramps(true);
-    let ramps2 = &mut rampifier.generate_ramps(false);
-
-    let ramp_count = ramps.len();
-    let ramp2_count = ramps2.len();
-
-    save.bricks.append(ramps);
-    save.bricks.append(ramps2);
+    let mut ramps = match options.floor_ramps {
+        true => rampifier.generate_ramps(true),
+        false => vec![],
+    };
+    let floor_count = ramps.len();
+    if options.ceiling_ramps {
+        ramps.append(&mut rampifier.generate_ramps(false));
+    }
+    let ceiling_count = ramps.len() - floor_count;
+
+    let (mut ramps, dropped): (Vec<_>, Vec<_>) = ramps.into_iter().partition(|ramp| {
+        let length = ramp_length(ramp);
+        (ramp.asset_name_index != wedge_index || options.wedges)
+            && length >= options.min_length
+            && options.max_length.map_or(true, |max| length <= max)
+    });
+    // voxels kept ramps sit on, which dropped ramps' bounds may overlap
+    let mut covered = vec![];
+    if !dropped.is_empty() {
+        covered = vec![false; solid.len()];
+        for i in ramps.iter().flat_map(|ramp| ramp_voxels(ramp, grid_size)) {
+            covered[i] = true;
+        }
+    }
 
-    println!(" - Processed {} voxels", vox_count);
-    println!(
-        " - Generated {} ramps in {}s\n",
-        ramp_count + ramp2_count,
-        now.elapsed().as_millis() as f64 / 1000.0
+    tracing::info!(
+        "Processed {vox_count} voxels, generated {floor_count} floor & {ceiling_count} ceiling ramps in {}s, keeping {}",
+        now.elapsed().as_millis() as f64 / 1000.0,
+        ramps.len()
     );
 
     // Sets the voxels occupied by ramps to empty.
@@ -190,12 +419,61 @@ pub fn rampify(save: &mut SaveData) {
 
     // Move grid back out of the rampifier to do further processing.
     let mut grid = rampifier.move_grid();
+    for i in dropped.iter().flat_map(|ramp| ramp_voxels(ramp, grid_size)) {
+        if !covered[i] && grid[i].is_none() {
+            grid[i] = solid[i];
+        }
+    }
+
+    if let Some(normals) = normals {
+        let mut turned = 0;
+        for ramp in &mut ramps {
+            let normal = ramp_cells(ramp)
+                .filter_map(|(i, j, k)| {
+                    let (x, y, z) = (i + min_bounds.0, j + min_bounds.1, k + min_bounds.2);
+                    // Coordinates are rotated
+                    let n = normals.get(&Vector3::new(x as isize, z as isize, y as isize))?;
+                    Some(Vector3::new(n.x, n.z, n.y))
+                })
+                .sum();
+            turned += orient_slope(ramp, normal) as usize;
+        }
+        tracing::info!("Turned {turned} ramps to face the surface");
+    }
+
+    if bricktype == BrickType::Microbricks {
+        ramps = ramps
+            .into_iter()
+            .flat_map(|ramp| match ramp.asset_name_index {
+                i if i == ramp_index => split_ramp(ramp, brick_index, wedge_index),
+                _ => vec![ramp],
+            })
+            .collect();
+    }
+
+    // Ramps are colored with the grid values, which index the color table
+    for ramp in &mut ramps {
+        if let BrickColor::Index(i) = ramp.color {
+            ramp.color = color_table[i as usize].brick_color();
+        }
+        rescale(ramp, voxel_size);
+    }
+    save.bricks.append(&mut ramps);
 
     /////////////////////////////////////////////////////////////
     //         PASS 3: GENERATE OPTIMIZED BRICK FILL           //
     /////////////////////////////////////////////////////////////
 
-    println!("Filling Gaps...");
+    tracing::info!("Filling gaps...");
+    let ramp_bricks = save.bricks.len();
+
+    let (max_w, max_l, max_h) = bricktype.max_voxels();
+    let max_fill = options.max_fill.max(1) as usize;
+    let (max_w, max_l, max_h) = (
+        (max_w as usize).min(max_fill),
+        (max_l as usize).min(max_fill),
+        (max_h as usize).min(max_fill),
+    );
 
     for z in 0..grid_size.2 {
         for y in 0..grid_size.1 {
@@ -208,21 +486,21 @@ pub fn rampify(save: &mut SaveData) {
                     let mut h = 1;
 
                     // todo: this can be done way better, but this is a shitty quick way to optimize bricks
-                    while can_box(&grid, val, &(x, y, z), &(w, l, h)) && h <= 64 {
+                    while can_box(&grid, val, &(x, y, z), &(w, l, h)) && h <= max_h {
                         h += 1;
                     }
 
                     h -= 1;
 
                     if h > 0 {
-                        while can_box(&grid, val, &(x, y, z), &(w, l, h)) && w <= 64 {
+                        while can_box(&grid, val, &(x, y, z), &(w, l, h)) && w <= max_w {
                             w += 1;
                         }
 
                         w -= 1;
 
                         if w > 0 {
-                            while can_box(&grid, val, &(x, y, z), &(w, l, h)) && l <= 64 {
+                            while can_box(&grid, val, &(x, y, z), &(w, l, h)) && l <= max_l {
                                 l += 1;
                             }
 
@@ -231,16 +509,22 @@ pub fn rampify(save: &mut SaveData) {
                             if l > 0 {
                                 box_remove(&mut grid, &(x, y, z), &(w, l, h));
 
-                                let size = (w as u32 * 5, l as u32 * 5, h as u32 * 2);
+                                let size = (
+                                    w as u32 * sx as u32,
+                                    l as u32 * sy as u32,
+                                    h as u32 * sz as u32,
+                                );
                                 {
-                                    let (x, y, z) = (x as i32 * 10, y as i32 * 10, z as i32 * 4);
+                                    let (x, y, z) =
+                                        (x as i32 * 2 * sx, y as i32 * 2 * sy, z as i32 * 2 * sz);
 
                                     brick.position =
                                         (x + size.0 as i32, y + size.1 as i32, z + size.2 as i32);
                                     brick.size = Size::Procedural(size.0, size.1, size.2);
                                 }
 
-                                brick.color = BrickColor::Index(val as u32);
+                                brick.asset_name_index = brick_index;
+                                brick.color = color_table[val as usize].brick_color();
 
                                 save.bricks.push(brick);
                             }
@@ -251,5 +535,8 @@ pub fn rampify(save: &mut SaveData) {
         }
     }
 
-    println!(" - Gaps filled.");
+    tracing::info!(
+        "Filled gaps with {} bricks",
+        save.bricks.len() - ramp_bricks
+    );
 }
@@ -1,41 +1,107 @@
 use crate::color::*;
-use crate::octree::{TreeBody, VoxelTree};
-use crate::BrickType;
+use crate::store::VoxelStore;
+use crate::voxelize::Voxel;
+use crate::{asset_max_voxels, BrickType};
 
 use brickadia::save as brs;
 use nalgebra::{Vector3, Vector4};
+use std::collections::{BTreeMap, HashMap, HashSet};
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Hash, clap::ValueEnum)]
+pub enum MergeStrategy {
+    /// Grow each brick from the first voxel found, in z, y, then x order
+    Greedy,
+    /// Cover each layer with its largest rectangles, then stack identical rectangles
+    Optimal,
+}
+
+/// Running OKLab average of the voxels merged into a box, used to limit lossy merging to
+/// similar colors. The bounds of the merged colors are kept too, so colors merged earlier are
+/// rechecked as the average drifts.
+#[derive(Clone, Copy)]
+struct RunningAverage {
+    tolerance: Option<f32>,
+    sum: Vector4<f32>,
+    count: f32,
+    min: Vector4<f32>,
+    max: Vector4<f32>,
+}
+
+impl RunningAverage {
+    fn new(tolerance: Option<f32>) -> Self {
+        Self {
+            tolerance,
+            sum: Vector4::zeros(),
+            count: 0.,
+            min: Vector4::repeat(f32::INFINITY),
+            max: Vector4::repeat(f32::NEG_INFINITY),
+        }
+    }
+
+    fn with(mut self, colors: &[Vector4<u8>]) -> Self {
+        for c in colors {
+            let c = rgb2oklab(*c);
+            self.sum += c;
+            self.min = self.min.inf(&c);
+            self.max = self.max.sup(&c);
+        }
+        self.count += colors.len() as f32;
+        self
+    }
+
+    /// Whether, with `colors` merged in, every merged color would be within the tolerance of the
+    /// new average. The farthest corner of their bounds stands in for the farthest color.
+    fn accepts(&self, colors: &[Vector4<u8>]) -> bool {
+        match self.tolerance {
+            None => true,
+            Some(tolerance) => {
+                let merged = self.with(colors);
+                let avg = merged.sum / merged.count;
+                let farthest = (avg - merged.min).abs().sup(&(merged.max - avg).abs());
+                farthest.magnitude() <= tolerance
+            }
+        }
+    }
+
+    fn add(&mut self, colors: &[Vector4<u8>]) {
+        if self.tolerance.is_some() {
+            *self = self.with(colors);
+        }
+    }
+}
 
 pub fn simplify_lossy(
-    octree: &mut VoxelTree<Vector4<u8>>,
+    octree: &mut dyn VoxelStore<Voxel>,
     save_data: &mut brs::SaveData,
     match_brickadia_colorset: bool,
     bricktype: BrickType,
     material_intensity: u32,
     max_merge: isize,
+    averaging: ColorAveraging,
+    glass_material: Option<u32>,
+    color_tolerance: Option<f32>,
 ) {
     let colorset = convert_colorset_to_hsv(&save_data.header2.colors);
-    let scales: (isize, isize, isize) = if bricktype == BrickType::Microbricks {
-        (1, 1, 1)
-    } else {
-        (5, 5, 2)
-    };
+    let scales = bricktype.voxel_size();
+    let limits = merge_limits(bricktype.asset_name(), scales, max_merge);
 
     loop {
         let mut colors = Vec::<Vector4<u8>>::new();
+        let mut slab = Vec::<Vector4<u8>>::new();
+        let mut running = RunningAverage::new(color_tolerance);
         let (x, y, z);
         {
-            let (location, voxel) = octree.get_any_mut_or_create();
+            let (location, voxel) = match octree.first() {
+                Some(first) => first,
+                None => break,
+            };
 
             x = location[0];
             y = location[1];
             z = location[2];
 
-            match voxel {
-                TreeBody::Leaf(leaf_color) => {
-                    colors.push(*leaf_color);
-                }
-                _ => break,
-            }
+            colors.push(voxel.color);
+            running.add(&colors);
         }
 
         let mut xp = x + 1;
@@ -45,10 +111,13 @@ pub fn simplify_lossy(
         // Expand z direction first due to octree ordering followed by y and x
         // Ensures blocks are simplified in the pattern of Morton coding
         // Saves us having to check in the negative directions
-        while zp - z < max_merge {
-            let voxel = octree.get_mut_or_create(Vector3::new(x, y, zp));
+        while zp - z < limits.z {
+            let voxel = octree.get(Vector3::new(x, y, zp));
             match voxel {
-                TreeBody::Leaf(leaf_color) => {
+                Some(Voxel {
+                    color: leaf_color, ..
+                }) if running.accepts(&[*leaf_color]) => {
+                    running.add(&[*leaf_color]);
                     colors.push(*leaf_color);
                     zp += 1
                 }
@@ -56,31 +125,39 @@ pub fn simplify_lossy(
             }
         }
 
-        while yp - y < max_merge {
+        while yp - y < limits.y {
             let mut pass = true;
+            slab.clear();
             for sz in z..zp {
-                let voxel = octree.get_mut_or_create(Vector3::new(x, yp, sz));
+                let voxel = octree.get(Vector3::new(x, yp, sz));
                 match voxel {
-                    TreeBody::Leaf(leaf_color) => colors.push(*leaf_color),
+                    Some(Voxel {
+                        color: leaf_color, ..
+                    }) => slab.push(*leaf_color),
                     _ => {
                         pass = false;
                         break;
                     }
                 }
             }
-            if !pass {
+            if !pass || !running.accepts(&slab) {
                 break;
             }
+            running.add(&slab);
+            colors.extend_from_slice(&slab);
             yp += 1;
         }
 
-        while xp - x < max_merge {
+        while xp - x < limits.x {
             let mut pass = true;
+            slab.clear();
             for sy in y..yp {
                 for sz in z..zp {
-                    let voxel = octree.get_mut_or_create(Vector3::new(xp, sy, sz));
+                    let voxel = octree.get(Vector3::new(xp, sy, sz));
                     match voxel {
-                        TreeBody::Leaf(leaf_color) => colors.push(*leaf_color),
+                        Some(Voxel {
+                            color: leaf_color, ..
+                        }) => slab.push(*leaf_color),
                         _ => {
                             pass = false;
                             break;
@@ -91,9 +168,11 @@ pub fn simplify_lossy(
                     break;
                 }
             }
-            if !pass {
+            if !pass || !running.accepts(&slab) {
                 break;
             }
+            running.add(&slab);
+            colors.extend_from_slice(&slab);
             xp += 1;
         }
 
@@ -103,24 +182,13 @@ pub fn simplify_lossy(
         for sx in x..xp {
             for sy in y..yp {
                 for sz in z..zp {
-                    let voxel = octree.get_mut_or_create(Vector3::new(sx, sy, sz));
-                    *voxel = TreeBody::Empty;
+                    octree.remove(Vector3::new(sx, sy, sz));
                 }
             }
         }
 
-        let avg_color = hsv_average(&colors);
-        let color = if match_brickadia_colorset {
-            brs::BrickColor::Index(match_hsv_to_colorset(&colorset, &avg_color) as u32)
-        } else {
-            let rgba = gamma_correct(hsv2rgb(avg_color));
-            brs::BrickColor::Unique(brs::Color {
-                r: rgba[0],
-                g: rgba[1],
-                b: rgba[2],
-                a: rgba[3],
-            })
-        };
+        let avg_color = average(&colors, averaging);
+        let color = brick_color(avg_color, &colorset, match_brickadia_colorset);
 
         let width = xp - x;
         let height = yp - y;
@@ -136,6 +204,10 @@ pub fn simplify_lossy(
             size: scaled_size(scales, (width, depth, height)),
             position: scaled_pos(scales, (width, depth, height), (x, z, y)),
             color,
+            material_index: match glass_material {
+                Some(index) if avg_color[3] < 255 => index,
+                _ => 0,
+            },
             material_intensity,
             ..Default::default()
         });
@@ -143,50 +215,48 @@ pub fn simplify_lossy(
 }
 
 pub fn simplify_lossless(
-    octree: &mut VoxelTree<Vector4<u8>>,
+    octree: &mut dyn VoxelStore<Voxel>,
     save_data: &mut brs::SaveData,
     match_brickadia_colorset: bool,
     bricktype: BrickType,
     material_intensity: u32,
     max_merge: isize,
+    glass_material: Option<u32>,
 ) {
-    let d: isize = 1 << octree.size;
-    let len = d + 1;
-
     let colorset = convert_colorset_to_hsv(&save_data.header2.colors);
 
-    let scales: (isize, isize, isize) = if bricktype == BrickType::Microbricks {
-        (1, 1, 1)
-    } else {
-        (5, 5, 2)
-    };
+    let scales = bricktype.voxel_size();
+    let limits = merge_limits(bricktype.asset_name(), scales, max_merge);
+
+    // partially transparent voxels are only merged with each other when they become glass
+    let is_glass = |c: &Vector4<u8>| glass_material.is_some() && c[3] < 255;
 
     loop {
         let matched_color;
         let unmatched_color;
+        let glass;
         let x;
         let y;
         let z;
         {
-            let (location, voxel) = octree.get_any_mut_or_create();
+            let (location, voxel) = match octree.first() {
+                Some(first) => first,
+                None => break,
+            };
 
             x = location[0];
             y = location[1];
             z = location[2];
 
-            match voxel {
-                TreeBody::Leaf(leaf_color) => {
-                    let final_color = gamma_correct(*leaf_color);
-                    matched_color = match_hsv_to_colorset(&colorset, &rgb2hsv(final_color));
-                    unmatched_color = brs::BrickColor::Unique(brs::Color {
-                        r: final_color[0],
-                        g: final_color[1],
-                        b: final_color[2],
-                        a: final_color[3],
-                    });
-                }
-                _ => break,
-            }
+            let final_color = gamma_correct(voxel.color);
+            matched_color = match_hsv_to_colorset(&colorset, &rgb2hsv(final_color));
+            glass = is_glass(&final_color);
+            unmatched_color = brs::BrickColor::Unique(brs::Color {
+                r: final_color[0],
+                g: final_color[1],
+                b: final_color[2],
+                a: final_color[3],
+            });
         }
 
         let mut xp = x + 1;
@@ -195,13 +265,15 @@ pub fn simplify_lossless(
 
         // Expand z direction first due to octree ordering followed by y
         // Ensures blocks are simplified in the pattern of Morton coding
-        while zp < len && (zp - z) < max_merge {
-            let voxel = octree.get_mut_or_create(Vector3::new(x, y, zp));
+        while zp - z < limits.z {
+            let voxel = octree.get(Vector3::new(x, y, zp));
             match voxel {
-                TreeBody::Leaf(leaf_color) => {
+                Some(Voxel {
+                    color: leaf_color, ..
+                }) => {
                     let final_color = gamma_correct(*leaf_color);
                     let color_temp = match_hsv_to_colorset(&colorset, &rgb2hsv(final_color));
-                    if color_temp != matched_color {
+                    if color_temp != matched_color || is_glass(&final_color) != glass {
                         break;
                     }
                     zp += 1;
@@ -210,15 +282,17 @@ pub fn simplify_lossless(
             }
         }
 
-        while yp < len && (yp - y) < max_merge {
+        while yp - y < limits.y {
             let mut pass = true;
             for sz in z..zp {
-                let voxel = octree.get_mut_or_create(Vector3::new(x, yp, sz));
+                let voxel = octree.get(Vector3::new(x, yp, sz));
                 match voxel {
-                    TreeBody::Leaf(leaf_color) => {
+                    Some(Voxel {
+                        color: leaf_color, ..
+                    }) => {
                         let final_color = gamma_correct(*leaf_color);
                         let color_temp = match_hsv_to_colorset(&colorset, &rgb2hsv(final_color));
-                        if color_temp != matched_color {
+                        if color_temp != matched_color || is_glass(&final_color) != glass {
                             pass = false;
                             break;
                         }
@@ -235,17 +309,19 @@ pub fn simplify_lossless(
             yp += 1;
         }
 
-        while xp < len && (xp - x) < max_merge {
+        while xp - x < limits.x {
             let mut pass = true;
             for sy in y..yp {
                 for sz in z..zp {
-                    let voxel = octree.get_mut_or_create(Vector3::new(xp, sy, sz));
+                    let voxel = octree.get(Vector3::new(xp, sy, sz));
                     match voxel {
-                        TreeBody::Leaf(leaf_color) => {
+                        Some(Voxel {
+                            color: leaf_color, ..
+                        }) => {
                             let final_color = gamma_correct(*leaf_color);
                             let color_temp =
                                 match_hsv_to_colorset(&colorset, &rgb2hsv(final_color));
-                            if color_temp != matched_color {
+                            if color_temp != matched_color || is_glass(&final_color) != glass {
                                 pass = false;
                                 break;
                             }
@@ -272,8 +348,7 @@ pub fn simplify_lossless(
         for sx in x..xp {
             for sy in y..yp {
                 for sz in z..zp {
-                    let voxel = octree.get_mut_or_create(Vector3::new(sx, sy, sz));
-                    *voxel = TreeBody::Empty;
+                    octree.remove(Vector3::new(sx, sy, sz));
                 }
             }
         }
@@ -299,13 +374,351 @@ pub fn simplify_lossless(
             position: scaled_pos(scales, (width, depth, height), (x, z, y)),
             color,
             owner_index: 1,
+            material_index: match glass_material {
+                Some(index) if glass => index,
+                _ => 0,
+            },
             material_intensity,
             ..Default::default()
         });
     }
 }
 
-fn scaled_size(scale: (isize, isize, isize), size: (isize, isize, isize)) -> brs::Size {
+/// Cover voxels of each color with few bricks: every horizontal layer is decomposed into its
+/// largest rectangles, and identical rectangles in consecutive layers are stacked into one brick.
+pub fn simplify_lossless_optimal(
+    octree: &mut dyn VoxelStore<Voxel>,
+    save_data: &mut brs::SaveData,
+    match_brickadia_colorset: bool,
+    bricktype: BrickType,
+    material_intensity: u32,
+    max_merge: isize,
+    glass_material: Option<u32>,
+) {
+    let colorset = convert_colorset_to_hsv(&save_data.header2.colors);
+
+    let scales = bricktype.voxel_size();
+    let limits = merge_limits(bricktype.asset_name(), scales, max_merge);
+
+    let is_glass = |c: &Vector4<u8>| glass_material.is_some() && c[3] < 255;
+
+    // Drain the octree, grouping voxels by the same key simplify_lossless merges by
+    let mut groups = BTreeMap::<(usize, bool), HashMap<Vector3<isize>, brs::BrickColor>>::new();
+    for (location, voxel) in octree.drain() {
+        let final_color = gamma_correct(voxel.color);
+        let matched_color = match_hsv_to_colorset(&colorset, &rgb2hsv(final_color));
+        let color = if match_brickadia_colorset {
+            brs::BrickColor::Index(matched_color as u32)
+        } else {
+            brs::BrickColor::Unique(brs::Color {
+                r: final_color[0],
+                g: final_color[1],
+                b: final_color[2],
+                a: final_color[3],
+            })
+        };
+        groups
+            .entry((matched_color, is_glass(&final_color)))
+            .or_default()
+            .insert(Vector3::new(location[0], location[1], location[2]), color);
+    }
+
+    for ((_, glass), voxels) in groups {
+        // y is the vertical axis of the save
+        let mut layers = BTreeMap::<isize, HashSet<(isize, isize)>>::new();
+        for v in voxels.keys() {
+            layers.entry(v.y).or_default().insert((v.x, v.z));
+        }
+
+        // rectangle (x, z, width, depth) -> (bottom layer, layer count)
+        let mut open = HashMap::<(isize, isize, isize, isize), (isize, isize)>::new();
+        let mut boxes = Vec::<(isize, isize, isize, isize, isize, isize)>::new();
+        let mut close = |(x, z, w, d): (isize, isize, isize, isize), (y, h): (isize, isize)| {
+            boxes.push((x, y, z, w, h, d))
+        };
+
+        let mut prev_y = None;
+        for (y, layer) in layers {
+            if prev_y.map_or(true, |p| p + 1 != y) {
+                for (rect, span) in open.drain() {
+                    close(rect, span);
+                }
+            }
+            prev_y = Some(y);
+
+            let mut next_open = HashMap::new();
+            for rect in layer_rectangles(layer, limits.x, limits.z) {
+                match open.remove(&rect) {
+                    Some((y0, h)) if h < limits.y => next_open.insert(rect, (y0, h + 1)),
+                    Some(span) => {
+                        close(rect, span);
+                        next_open.insert(rect, (y, 1))
+                    }
+                    None => next_open.insert(rect, (y, 1)),
+                };
+            }
+            for (rect, span) in open.drain() {
+                close(rect, span);
+            }
+            open = next_open;
+        }
+        for (rect, span) in open.drain() {
+            close(rect, span);
+        }
+
+        boxes.sort_unstable();
+        for (x, y, z, width, height, depth) in boxes {
+            save_data.bricks.push(brs::Brick {
+                asset_name_index: if bricktype == BrickType::Microbricks {
+                    0
+                } else {
+                    1
+                },
+                // Coordinates are rotated
+                size: scaled_size(scales, (width, depth, height)),
+                position: scaled_pos(scales, (width, depth, height), (x, z, y)),
+                color: voxels[&Vector3::new(x, y, z)].clone(),
+                owner_index: 1,
+                material_index: match glass_material {
+                    Some(index) if glass => index,
+                    _ => 0,
+                },
+                material_intensity,
+                ..Default::default()
+            });
+        }
+    }
+}
+
+/// Split a layer of voxels into rectangles (x, z, width, depth), largest first
+fn layer_rectangles(
+    mut cells: HashSet<(isize, isize)>,
+    max_width: isize,
+    max_depth: isize,
+) -> Vec<(isize, isize, isize, isize)> {
+    let mut res = vec![];
+    // Searching each connected region separately keeps sparse layers cheap
+    while let Some(&start) = cells.iter().next() {
+        let mut region = vec![start];
+        cells.remove(&start);
+        let mut i = 0;
+        while i < region.len() {
+            let (x, z) = region[i];
+            for n in [(x + 1, z), (x - 1, z), (x, z + 1), (x, z - 1)] {
+                if cells.remove(&n) {
+                    region.push(n);
+                }
+            }
+            i += 1;
+        }
+
+        let min_x = region.iter().map(|c| c.0).min().unwrap();
+        let min_z = region.iter().map(|c| c.1).min().unwrap();
+        let w = (region.iter().map(|c| c.0).max().unwrap() - min_x + 1) as usize;
+        let d = (region.iter().map(|c| c.1).max().unwrap() - min_z + 1) as usize;
+        let mut grid = vec![false; w * d];
+        for (x, z) in &region {
+            grid[(x - min_x) as usize + (z - min_z) as usize * w] = true;
+        }
+
+        while let Some((x, z, rw, rd)) = largest_rectangle(&grid, w, d) {
+            let (rw, rd) = (rw.min(max_width as usize), rd.min(max_depth as usize));
+            for sz in z..z + rd {
+                grid[x + sz * w..x + rw + sz * w].fill(false);
+            }
+            res.push((
+                min_x + x as isize,
+                min_z + z as isize,
+                rw as isize,
+                rd as isize,
+            ));
+        }
+    }
+    res
+}
+
+/// Find the largest rectangle (x, z, width, depth) of filled cells in a `w` by `d` grid
+fn largest_rectangle(grid: &[bool], w: usize, d: usize) -> Option<(usize, usize, usize, usize)> {
+    let mut best = None;
+    let mut best_area = 0;
+    let mut heights = vec![0usize; w];
+    let mut stack = Vec::<usize>::with_capacity(w + 1);
+    for z in 0..d {
+        for x in 0..w {
+            heights[x] = if grid[x + z * w] { heights[x] + 1 } else { 0 };
+        }
+        // largest rectangle under the histogram of filled cells ending at this row
+        stack.clear();
+        for x in 0..=w {
+            let h = if x < w { heights[x] } else { 0 };
+            while let Some(&top) = stack.last() {
+                if heights[top] < h {
+                    break;
+                }
+                stack.pop();
+                let left = stack.last().map_or(0, |l| l + 1);
+                let area = heights[top] * (x - left);
+                if area > best_area {
+                    best_area = area;
+                    best = Some((left, z + 1 - heights[top], x - left, heights[top]));
+                }
+            }
+            stack.push(x);
+        }
+    }
+    best
+}
+
+/// Footprints of standard bricks & plates in studs, largest first
+const STANDARD_FOOTPRINTS: [(isize, isize); 11] = [
+    (2, 8),
+    (2, 6),
+    (2, 4),
+    (1, 8),
+    (2, 3),
+    (1, 6),
+    (2, 2),
+    (1, 4),
+    (1, 3),
+    (1, 2),
+    (1, 1),
+];
+
+/// Plates per standard brick
+const BRICK_HEIGHT: isize = 3;
+
+/// Split the procedural bricks in `save_data.bricks[start..]` into standard sizes: single plates
+/// or whole bricks tall, with common footprints. Layers alternate orientation so that the seams
+/// between bricks are staggered, like a real brick build.
+pub fn standardize_bricks(save_data: &mut brs::SaveData, start: usize, bricktype: BrickType) {
+    let scales = bricktype.voxel_size();
+    let (sx, sy, sz) = scales;
+    for brick in save_data.bricks.split_off(start) {
+        let (w, d, h) = match brick.size {
+            brs::Size::Procedural(w, d, h) => (w as isize / sx, d as isize / sy, h as isize / sz),
+            _ => {
+                save_data.bricks.push(brick);
+                continue;
+            }
+        };
+        // position of the brick's minimum corner, in voxels
+        let (x, y, z) = (
+            (brick.position.0 as isize - w * sx) / (2 * sx),
+            (brick.position.1 as isize - d * sy) / (2 * sy),
+            (brick.position.2 as isize - h * sz) / (2 * sz),
+        );
+
+        // whole bricks at the bottom, remaining plates on top
+        let mut layers = (0..h / BRICK_HEIGHT)
+            .map(|i| (i * BRICK_HEIGHT, BRICK_HEIGHT))
+            .collect::<Vec<_>>();
+        for i in h - h % BRICK_HEIGHT..h {
+            layers.push((i, 1));
+        }
+
+        let footprints = standard_footprints(w, d);
+        let transposed = standard_footprints(d, w);
+        for (i, (lz, lh)) in layers.into_iter().enumerate() {
+            let rects: Box<dyn Iterator<Item = (isize, isize, isize, isize)>> = if i % 2 == 0 {
+                Box::new(footprints.iter().copied())
+            } else {
+                Box::new(transposed.iter().map(|&(y, x, d, w)| (x, y, w, d)))
+            };
+            for (fx, fy, fw, fd) in rects {
+                save_data.bricks.push(brs::Brick {
+                    size: scaled_size(scales, (fw, fd, lh)),
+                    position: scaled_pos(scales, (fw, fd, lh), (x + fx, y + fy, z + lz)),
+                    ..brick.clone()
+                });
+            }
+        }
+    }
+}
+
+/// Cover a `w` by `d` rectangle with standard footprints (x, y, width, depth)
+fn standard_footprints(w: isize, d: isize) -> Vec<(isize, isize, isize, isize)> {
+    cover_rectangle(w, d, &STANDARD_FOOTPRINTS)
+        .into_iter()
+        .map(|(x, y, i, rotated)| {
+            let (a, b) = STANDARD_FOOTPRINTS[i];
+            match rotated {
+                true => (x, y, b, a),
+                false => (x, y, a, b),
+            }
+        })
+        .collect()
+}
+
+/// Greedily cover a `w` by `d` rectangle with footprints (width, depth), trying them in order.
+/// Returns the position of each piece, the index of its footprint, & whether it's rotated by 90
+/// degrees. Cells that no footprint fits into are left uncovered.
+pub fn cover_rectangle(
+    w: isize,
+    d: isize,
+    footprints: &[(isize, isize)],
+) -> Vec<(isize, isize, usize, bool)> {
+    let mut covered = vec![false; (w * d) as usize];
+    let mut res = vec![];
+    for cy in 0..d {
+        for cx in 0..w {
+            if covered[(cx + cy * w) as usize] {
+                continue;
+            }
+            let fits = |fw: isize, fd: isize| {
+                cx + fw <= w
+                    && cy + fd <= d
+                    && (cy..cy + fd).all(|y| (cx..cx + fw).all(|x| !covered[(x + y * w) as usize]))
+            };
+            let piece = footprints
+                .iter()
+                .enumerate()
+                .flat_map(|(i, &(a, b))| {
+                    // square pieces gain nothing from being rotated
+                    let rotated = (a != b).then_some((i, true, b, a));
+                    std::iter::once((i, false, a, b)).chain(rotated)
+                })
+                .find(|&(_, _, fw, fd)| fits(fw, fd));
+            if let Some((i, rotated, fw, fd)) = piece {
+                for y in cy..cy + fd {
+                    for x in cx..cx + fw {
+                        covered[(x + y * w) as usize] = true;
+                    }
+                }
+                res.push((cx, cy, i, rotated));
+            }
+        }
+    }
+    res
+}
+
+/// Brick color for a voxel color, either matched to the colorset or as a unique color
+pub fn brick_color(
+    color: Vector4<u8>,
+    colorset: &[Vector4<f32>],
+    match_brickadia_colorset: bool,
+) -> brs::BrickColor {
+    if match_brickadia_colorset {
+        brs::BrickColor::Index(match_hsv_to_colorset(colorset, &rgb2hsv(color)) as u32)
+    } else {
+        let rgba = gamma_correct(color);
+        brs::BrickColor::Unique(brs::Color {
+            r: rgba[0],
+            g: rgba[1],
+            b: rgba[2],
+            a: rgba[3],
+        })
+    }
+}
+
+/// Per-axis merge limits in octree coordinates, combining `max_merge` with the largest brick
+/// Brickadia accepts for `asset`
+fn merge_limits(asset: &str, scales: (isize, isize, isize), max_merge: isize) -> Vector3<isize> {
+    let (x, y, z) = asset_max_voxels(asset, scales);
+    // Coordinates are rotated
+    Vector3::new(x.min(max_merge), z.min(max_merge), y.min(max_merge))
+}
+
+pub fn scaled_size(scale: (isize, isize, isize), size: (isize, isize, isize)) -> brs::Size {
     brs::Size::Procedural(
         (scale.0 * size.0) as u32,
         (scale.1 * size.1) as u32,
@@ -313,7 +726,7 @@ fn scaled_size(scale: (isize, isize, isize), size: (isize, isize, isize)) -> brs
     )
 }
 
-fn scaled_pos(
+pub fn scaled_pos(
     scale: (isize, isize, isize),
     size: (isize, isize, isize),
     pos: (isize, isize, isize),
@@ -324,3 +737,125 @@ fn scaled_pos(
         (scale.2 * size.2 + 2 * scale.2 * pos.2) as i32,
     )
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(rows: &[&str]) -> (Vec<bool>, usize, usize) {
+        let grid = rows
+            .iter()
+            .flat_map(|row| row.chars().map(|c| c == '#'))
+            .collect();
+        (grid, rows[0].len(), rows.len())
+    }
+
+    /// Checks that pieces from `cover_rectangle` stay in bounds & don't overlap, returning
+    /// the cells they cover
+    fn covered(w: isize, d: isize, footprints: &[(isize, isize)]) -> Vec<bool> {
+        let mut covered = vec![false; (w * d) as usize];
+        for (x, y, i, rotated) in cover_rectangle(w, d, footprints) {
+            let (mut fw, mut fd) = footprints[i];
+            if rotated {
+                (fw, fd) = (fd, fw);
+            }
+            assert!(x >= 0 && y >= 0 && x + fw <= w && y + fd <= d);
+            for cy in y..y + fd {
+                for cx in x..x + fw {
+                    let cell = &mut covered[(cx + cy * w) as usize];
+                    assert!(!*cell, "({cx}, {cy}) is covered twice");
+                    *cell = true;
+                }
+            }
+        }
+        covered
+    }
+
+    #[test]
+    fn largest_rectangle_of_empty_grids() {
+        assert_eq!(largest_rectangle(&[], 0, 0), None);
+        let (grid, w, d) = parse(&["...", "..."]);
+        assert_eq!(largest_rectangle(&grid, w, d), None);
+    }
+
+    #[test]
+    fn largest_rectangle_of_full_grid() {
+        let (grid, w, d) = parse(&["###", "###"]);
+        assert_eq!(largest_rectangle(&grid, w, d), Some((0, 0, 3, 2)));
+    }
+
+    #[test]
+    fn largest_rectangle_of_ragged_grid() {
+        let (grid, w, d) = parse(&["##...", "####.", ".####", ".###."]);
+        assert_eq!(largest_rectangle(&grid, w, d), Some((1, 1, 3, 3)));
+        let (grid, w, d) = parse(&["#.###", "#.###", "#....", "#.##."]);
+        assert_eq!(largest_rectangle(&grid, w, d), Some((2, 0, 3, 2)));
+    }
+
+    #[test]
+    fn largest_rectangle_matches_brute_force() {
+        let (w, d) = (9, 7);
+        let mut seed = 12345u32;
+        for _ in 0..50 {
+            let grid = (0..w * d)
+                .map(|_| {
+                    seed = seed.wrapping_mul(1664525).wrapping_add(1013904223);
+                    seed >> 28 != 0
+                })
+                .collect::<Vec<_>>();
+            let filled = |x: usize, z: usize, rw: usize, rd: usize| {
+                (z..z + rd).all(|z| (x..x + rw).all(|x| grid[x + z * w]))
+            };
+            let mut best = 0;
+            for z in 0..d {
+                for x in 0..w {
+                    for rd in 1..=d - z {
+                        for rw in 1..=w - x {
+                            if filled(x, z, rw, rd) {
+                                best = best.max(rw * rd);
+                            }
+                        }
+                    }
+                }
+            }
+            match largest_rectangle(&grid, w, d) {
+                Some((x, z, rw, rd)) => {
+                    assert!(filled(x, z, rw, rd));
+                    assert_eq!(rw * rd, best);
+                }
+                None => assert_eq!(best, 0),
+            }
+        }
+    }
+
+    #[test]
+    fn cover_rectangle_prefers_earlier_footprints() {
+        assert_eq!(
+            cover_rectangle(4, 4, &STANDARD_FOOTPRINTS),
+            vec![(0, 0, 2, false), (2, 0, 2, false)]
+        );
+        assert_eq!(cover_rectangle(8, 2, &[(2, 8)]), vec![(0, 0, 0, true)]);
+        assert_eq!(cover_rectangle(2, 2, &[(2, 2)]), vec![(0, 0, 0, false)]);
+    }
+
+    #[test]
+    fn cover_rectangle_leaves_unfit_cells() {
+        let cells = covered(3, 3, &[(2, 2)]);
+        assert_eq!(cells.iter().filter(|c| **c).count(), 4);
+        assert!(covered(1, 5, &[(2, 2)]).iter().all(|c| !c));
+    }
+
+    #[test]
+    fn standard_footprints_cover_everything() {
+        for w in 1..12 {
+            for d in 1..12 {
+                assert!(covered(w, d, &STANDARD_FOOTPRINTS).iter().all(|c| *c));
+                let area = standard_footprints(w, d)
+                    .into_iter()
+                    .map(|(_, _, fw, fd)| fw * fd)
+                    .sum::<isize>();
+                assert_eq!(area, w * d);
+            }
+        }
+    }
+}
@@ -0,0 +1,103 @@
+use std::collections::HashSet;
+
+use brickadia::save as brs;
+use nalgebra::Vector3;
+
+use crate::asset_index;
+use crate::color::convert_colorset_to_hsv;
+use crate::orientation::{find_orientation, normal_signs};
+use crate::simplify::{brick_color, scaled_pos};
+use crate::store::VoxelStore;
+use crate::voxelize::Voxel;
+
+/// Microwedge assets, with the normal of their sloped face when placed with `Direction::ZPositive`
+/// & `Rotation::Deg0`, in the brick space of [`orient`](crate::orientation::orient). The wedge is
+/// taken to rise from +x to -x with its slope facing up & along +x, and both corners to face +x, +y
+/// & +z. No save in this repo holds wedges (reference.brs is a single plate), so these haven't been
+/// checked against the game; wedges facing into the model would mean a wrong entry here.
+const WEDGE_ASSETS: [(&str, [i32; 3]); 3] = [
+    ("PB_DefaultMicroWedge", [1, 0, 1]),
+    ("PB_DefaultMicroWedgeCorner", [1, 1, 1]),
+    ("PB_DefaultMicroWedgeInnerCorner", [1, 1, 1]),
+];
+
+/// Smallest normal component for a surface to count as sloped along an axis. A wedge's slope is
+/// tilted 45° from both axes, so this is sin 22.5°: a normal tilted at least 22.5° towards an axis
+/// is closer to a wedge's slope than to a flat face.
+const SLOPE_THRESHOLD: f32 = 0.3827;
+
+/// Replace microbricks on sloped surfaces with microwedges facing along the surface normal.
+///
+/// A voxel becomes a wedge when its normal is sloped along two axes & both neighbors in those
+/// directions are empty, an outer corner when sloped along three axes with all three neighbors
+/// empty, and an inner corner when only two of the three are empty. Every other voxel is left in
+/// the octree to be simplified as usual.
+pub fn smooth_microwedges(
+    octree: &mut dyn VoxelStore<Voxel>,
+    save_data: &mut brs::SaveData,
+    match_brickadia_colorset: bool,
+    material_intensity: u32,
+    glass_material: Option<u32>,
+) {
+    let colorset = convert_colorset_to_hsv(&save_data.header2.colors);
+    let assets = WEDGE_ASSETS.map(|(name, normal)| {
+        (
+            asset_index(save_data, name),
+            Vector3::new(normal[0], normal[1], normal[2]),
+        )
+    });
+
+    // kept in the store's order, so bricks are placed in the same order on every run
+    let voxels = octree.drain().collect::<Vec<_>>();
+    let filled = voxels.iter().map(|(pos, _)| *pos).collect::<HashSet<_>>();
+
+    let mut wedges = 0;
+    for (pos, voxel) in &voxels {
+        let signs = normal_signs(&voxel.normal, SLOPE_THRESHOLD);
+        let sloped = (0..3).filter(|a| signs[*a] != 0).count();
+        let open = (0..3)
+            .filter(|a| signs[*a] != 0)
+            .filter(|a| {
+                let mut neighbor = *pos;
+                neighbor[*a] += signs[*a] as isize;
+                !filled.contains(&neighbor)
+            })
+            .count();
+
+        let asset = match (sloped, open) {
+            (2, 2) => Some(assets[0]),
+            (3, 3) => Some(assets[1]),
+            (3, 2) => Some(assets[2]),
+            _ => None,
+        };
+        // Coordinates are rotated
+        let orientation = asset.and_then(|(index, normal)| {
+            find_orientation(normal, Vector3::new(signs.x, signs.z, signs.y)).map(|o| (index, o))
+        });
+
+        match orientation {
+            Some((index, (direction, rotation))) => {
+                wedges += 1;
+                save_data.bricks.push(brs::Brick {
+                    asset_name_index: index,
+                    size: brs::Size::Procedural(1, 1, 1),
+                    position: scaled_pos((1, 1, 1), (1, 1, 1), (pos.x, pos.z, pos.y)),
+                    direction,
+                    rotation,
+                    color: brick_color(voxel.color, &colorset, match_brickadia_colorset),
+                    owner_index: 1,
+                    material_index: match glass_material {
+                        Some(index) if voxel.color[3] < 255 => index,
+                        _ => 0,
+                    },
+                    material_intensity,
+                    ..Default::default()
+                });
+            }
+            None => {
+                octree.insert(*pos, *voxel);
+            }
+        }
+    }
+    tracing::info!("Placed {wedges} microwedges");
+}
@@ -0,0 +1,117 @@
+//! Common interface over the sparse voxel containers
+
+use nalgebra::Vector3;
+
+use crate::grid::SparseGrid;
+use crate::octree::Octree;
+
+/// Which container voxels are kept in during a conversion
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Hash, clap::ValueEnum)]
+pub enum VoxelStorage {
+    /// Octree grown to cover the whole model; compact for roughly cubic models
+    Octree,
+    /// Hash map of 16³ chunks; keeps long, thin models from needing a huge octree
+    Grid,
+}
+
+impl Default for VoxelStorage {
+    fn default() -> Self {
+        Self::Octree
+    }
+}
+
+impl VoxelStorage {
+    pub fn create<T: 'static>(&self) -> Box<dyn VoxelStore<T>> {
+        match self {
+            Self::Octree => Box::new(Octree::new()),
+            Self::Grid => Box::new(SparseGrid::new()),
+        }
+    }
+}
+
+/// Sparse map from voxel positions to values
+pub trait VoxelStore<T> {
+    fn len(&self) -> usize;
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+    fn get(&self, pos: Vector3<isize>) -> Option<&T>;
+    fn get_mut(&mut self, pos: Vector3<isize>) -> Option<&mut T>;
+    /// Insert a voxel, returning the value it replaced
+    fn insert(&mut self, pos: Vector3<isize>, value: T) -> Option<T>;
+    fn remove(&mut self, pos: Vector3<isize>) -> Option<T>;
+    /// First voxel in iteration order. Voxels come in ascending z before y before x within small
+    /// blocks, which the greedy simplifiers rely on to grow bricks in the positive directions.
+    fn first(&self) -> Option<(Vector3<isize>, &T)>;
+    fn iter(&self) -> Box<dyn Iterator<Item = (Vector3<isize>, &T)> + '_>;
+    /// Remove every voxel, in iteration order
+    fn drain(&mut self) -> Box<dyn Iterator<Item = (Vector3<isize>, T)>>;
+}
+
+impl<T: 'static> VoxelStore<T> for Octree<T> {
+    fn len(&self) -> usize {
+        Octree::len(self)
+    }
+
+    fn get(&self, pos: Vector3<isize>) -> Option<&T> {
+        Octree::get(self, pos)
+    }
+
+    fn get_mut(&mut self, pos: Vector3<isize>) -> Option<&mut T> {
+        Octree::get_mut(self, pos)
+    }
+
+    fn insert(&mut self, pos: Vector3<isize>, value: T) -> Option<T> {
+        Octree::insert(self, pos, value)
+    }
+
+    fn remove(&mut self, pos: Vector3<isize>) -> Option<T> {
+        Octree::remove(self, pos)
+    }
+
+    fn first(&self) -> Option<(Vector3<isize>, &T)> {
+        Octree::first(self)
+    }
+
+    fn iter(&self) -> Box<dyn Iterator<Item = (Vector3<isize>, &T)> + '_> {
+        Box::new(Octree::iter(self))
+    }
+
+    fn drain(&mut self) -> Box<dyn Iterator<Item = (Vector3<isize>, T)>> {
+        Box::new(Octree::drain(self))
+    }
+}
+
+impl<T: 'static> VoxelStore<T> for SparseGrid<T> {
+    fn len(&self) -> usize {
+        SparseGrid::len(self)
+    }
+
+    fn get(&self, pos: Vector3<isize>) -> Option<&T> {
+        SparseGrid::get(self, pos)
+    }
+
+    fn get_mut(&mut self, pos: Vector3<isize>) -> Option<&mut T> {
+        SparseGrid::get_mut(self, pos)
+    }
+
+    fn insert(&mut self, pos: Vector3<isize>, value: T) -> Option<T> {
+        SparseGrid::insert(self, pos, value)
+    }
+
+    fn remove(&mut self, pos: Vector3<isize>) -> Option<T> {
+        SparseGrid::remove(self, pos)
+    }
+
+    fn first(&self) -> Option<(Vector3<isize>, &T)> {
+        SparseGrid::first(self)
+    }
+
+    fn iter(&self) -> Box<dyn Iterator<Item = (Vector3<isize>, &T)> + '_> {
+        Box::new(SparseGrid::iter(self))
+    }
+
+    fn drain(&mut self) -> Box<dyn Iterator<Item = (Vector3<isize>, T)>> {
+        Box::new(SparseGrid::drain(self))
+    }
+}
@@ -0,0 +1,392 @@
+//! Out-of-core conversion for models whose voxels don't fit in memory. Triangles, lines & points
+//! are partitioned into cubic tiles on disk, and each tile is voxelized & handed off to be simplified
+//! on its own, so only one tile's primitives & voxels are held at a time.
+//!
+//! Every voxel belongs to exactly one tile, and every primitive is written to each tile it comes
+//! within a voxel of, so tile seams never duplicate or drop voxels.
+
+use std::{
+    collections::BTreeMap,
+    fs::{self, File, OpenOptions},
+    io::{self, BufReader, Read, Write},
+    path::{Path, PathBuf},
+};
+
+use nalgebra::{Vector2, Vector3, Vector4};
+use uuid::Uuid;
+
+use crate::load::ObjRegistry;
+use crate::store::VoxelStore;
+use crate::voxelize::{
+    load_textures, voxel_transform, voxelize_points, voxelize_segments, voxelize_triangles, Point,
+    Segment, Triangle, Voxel, VoxelizeOptions,
+};
+use crate::BrickType;
+
+/// Tiles are never split below this many voxels across
+const MIN_TILE_SIZE: isize = 16;
+/// Rough memory needed per voxel of a tile, counting the copies simplification makes
+const BYTES_PER_VOXEL: u64 = 128;
+/// Rough number of voxels per unit of a tile's face area, for surfaces crossing the tile
+const SURFACE_DENSITY: u64 = 4;
+/// Size of a triangle or segment on disk after its flags: material, three vertices, and three
+/// UVs. Segments repeat their last vertex.
+const FACE_BYTES: usize = 4 + 9 * 4 + 6 * 4;
+/// Size of a point on disk after its flags: position, color, and normal
+const POINT_BYTES: usize = 3 * 4 + 4 + 3 * 4;
+const NO_MATERIAL: u32 = u32::MAX;
+const HAS_UVS: u8 = 1;
+const SEGMENT: u8 = 2;
+const POINT: u8 = 4;
+
+type TileKey = (isize, isize, isize);
+
+/// What each record in a tile file holds
+enum Primitive {
+    Triangle(Triangle),
+    Segment(Segment),
+    Point(Point),
+}
+
+/// Everything read from one tile's file
+#[derive(Default)]
+struct Primitives {
+    triangles: Vec<Triangle>,
+    segments: Vec<Segment>,
+    points: Vec<Point>,
+}
+
+/// Largest power-of-two tile size whose voxels are expected to fit in `memory_budget` bytes
+pub fn tile_size(memory_budget: u64) -> isize {
+    let voxels = memory_budget / BYTES_PER_VOXEL;
+    let size = ((voxels / SURFACE_DENSITY) as f64).sqrt() as u64;
+    ((size + 1).next_power_of_two() / 2).max(MIN_TILE_SIZE as u64) as isize
+}
+
+/// Voxelize the loaded models in tiles fitting `memory_budget` bytes, calling `write` with the
+/// voxels of each non-empty tile in turn. The registry is consumed so each model set's meshes
+/// can be freed as soon as they've been partitioned.
+pub fn voxelize_tiled<E: From<io::Error>>(
+    registry: ObjRegistry,
+    scale: f32,
+    bricktype: BrickType,
+    options: &VoxelizeOptions,
+    memory_budget: u64,
+    mut write: impl FnMut(&mut dyn VoxelStore<Voxel>) -> Result<(), E>,
+) -> Result<(), E> {
+    let dir = TempDir::new()?;
+    let size = tile_size(memory_budget);
+    // Triangles take several times their size on disk once loaded & clipped during voxelization
+    let triangle_budget = (memory_budget / 16) as usize;
+    // Lines reach as far as their radius past the voxels they pass through
+    let line_margin = 1 + options.line_thickness.map_or(0, |t| t as isize / 2);
+    tracing::info!(
+        "Partitioning triangles into tiles of {size}³ voxels in {:?}",
+        dir.0
+    );
+
+    let textures = load_textures(&registry.images, options);
+    let transform = voxel_transform(scale, bricktype);
+    let mut materials = vec![];
+    let mut tiles = TileWriter::new(&dir.0, size, None, triangle_budget, line_margin);
+    for mut set in registry.model_sets {
+        let offset = materials.len();
+        set.transform(&transform);
+        for mut triangle in set.triangles() {
+            triangle.material_id = triangle.material_id.map(|id| id + offset);
+            tiles.push(&Primitive::Triangle(triangle))?;
+        }
+        if options.line_thickness.is_some() {
+            for mut segment in set.segments() {
+                segment.material_id = segment.material_id.map(|id| id + offset);
+                tiles.push(&Primitive::Segment(segment))?;
+            }
+        }
+        for point in set.points() {
+            tiles.push(&Primitive::Point(point))?;
+        }
+        // only taken once partitioned, since triangles() still indexes set.materials
+        materials.append(&mut set.materials);
+    }
+    let tiles = tiles.finish()?;
+
+    let mut voxelize = |primitives: Primitives, origin, size| -> Result<(), E> {
+        let mut store = options.storage.create();
+        voxelize_triangles(
+            primitives.triangles,
+            origin,
+            size,
+            &textures,
+            &materials,
+            options,
+            store.as_mut(),
+        );
+        voxelize_segments(
+            &primitives.segments,
+            Some((origin, size)),
+            &textures,
+            &materials,
+            options,
+            store.as_mut(),
+        );
+        voxelize_points(
+            &primitives.points,
+            Some((origin, size)),
+            options,
+            store.as_mut(),
+        );
+        match store.is_empty() {
+            true => Ok(()),
+            false => write(store.as_mut()),
+        }
+    };
+    let count = tiles.len();
+    for (i, (key, path)) in tiles.into_iter().enumerate() {
+        tracing::info!("Voxelizing tile {} of {count}...", i + 1);
+        let origin = Vector3::new(key.0, key.1, key.2) * size;
+        voxelize_tile(
+            &dir.0,
+            origin,
+            size,
+            &path,
+            triangle_budget,
+            line_margin,
+            &mut voxelize,
+        )?;
+    }
+    Ok(())
+}
+
+/// Voxelize the tile spanning `size` voxels from `origin`, splitting it into octants first if
+/// its primitives exceed `triangle_budget` bytes
+fn voxelize_tile<E: From<io::Error>>(
+    dir: &Path,
+    origin: Vector3<isize>,
+    size: isize,
+    path: &Path,
+    triangle_budget: usize,
+    line_margin: isize,
+    voxelize: &mut dyn FnMut(Primitives, Vector3<isize>, isize) -> Result<(), E>,
+) -> Result<(), E> {
+    let mut reader = BufReader::new(File::open(path)?);
+    if fs::metadata(path)?.len() as usize <= triangle_budget || size <= MIN_TILE_SIZE {
+        let mut primitives = Primitives::default();
+        while let Some(primitive) = read_primitive(&mut reader)? {
+            match primitive {
+                Primitive::Triangle(triangle) => primitives.triangles.push(triangle),
+                Primitive::Segment(segment) => primitives.segments.push(segment),
+                Primitive::Point(point) => primitives.points.push(point),
+            }
+        }
+        fs::remove_file(path)?;
+        return voxelize(primitives, origin, size);
+    }
+
+    let half = size / 2;
+    tracing::debug!("Splitting dense tile at {origin:?} into {half}³ octants");
+    let mut octants = TileWriter::new(dir, half, Some(origin / half), triangle_budget, line_margin);
+    while let Some(primitive) = read_primitive(&mut reader)? {
+        octants.push(&primitive)?;
+    }
+    fs::remove_file(path)?;
+    // Octant keys sort in Morton order, like the octree's children
+    for (key, path) in octants.finish()? {
+        let origin = Vector3::new(key.0, key.1, key.2) * half;
+        voxelize_tile(
+            dir,
+            origin,
+            half,
+            &path,
+            triangle_budget,
+            line_margin,
+            voxelize,
+        )?;
+    }
+    Ok(())
+}
+
+/// Writes primitives to the files of every tile they might touch, buffering them in memory and
+/// only keeping files open while flushing, so any number of tiles can be written at once
+struct TileWriter<'dir> {
+    dir: &'dir Path,
+    size: isize,
+    /// Key of the lowest octant when splitting a tile, limiting triangles to its 8 octants
+    octants: Option<Vector3<isize>>,
+    buffers: BTreeMap<TileKey, Vec<u8>>,
+    paths: BTreeMap<TileKey, PathBuf>,
+    buffered: usize,
+    limit: usize,
+    /// Voxels of margin around lines & points
+    line_margin: isize,
+}
+
+impl<'dir> TileWriter<'dir> {
+    fn new(
+        dir: &'dir Path,
+        size: isize,
+        octants: Option<Vector3<isize>>,
+        limit: usize,
+        line_margin: isize,
+    ) -> Self {
+        Self {
+            dir,
+            size,
+            octants,
+            buffers: BTreeMap::new(),
+            paths: BTreeMap::new(),
+            buffered: 0,
+            limit,
+            line_margin,
+        }
+    }
+
+    fn push(&mut self, primitive: &Primitive) -> io::Result<()> {
+        // A voxel of margin makes sure triangles touching a tile's boundary are in both tiles
+        // Points only ever fill the voxel they're in
+        let (vertices, margin) = match primitive {
+            Primitive::Triangle(triangle) => (&triangle.vertices[..], 1),
+            Primitive::Segment(segment) => (&segment.vertices[..], self.line_margin),
+            Primitive::Point(point) => (std::slice::from_ref(&point.position), 0),
+        };
+        let (mut lo, mut hi) = (Vector3::repeat(isize::MAX), Vector3::repeat(isize::MIN));
+        for v in vertices {
+            lo = lo.inf(&v.map(|c| (c.floor() as isize - margin).div_euclid(self.size)));
+            hi = hi.sup(&v.map(|c| (c.ceil() as isize + margin).div_euclid(self.size)));
+        }
+        if let Some(first) = self.octants {
+            lo = lo.sup(&first);
+            hi = hi.inf(&first.add_scalar(1));
+        }
+
+        let mut record = Vec::with_capacity(1 + FACE_BYTES);
+        write_primitive(&mut record, primitive);
+        for x in lo.x..=hi.x {
+            for y in lo.y..=hi.y {
+                for z in lo.z..=hi.z {
+                    self.buffers
+                        .entry((x, y, z))
+                        .or_default()
+                        .extend_from_slice(&record);
+                    self.buffered += record.len();
+                }
+            }
+        }
+        if self.buffered > self.limit {
+            self.flush()?;
+        }
+        Ok(())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        for (key, buffer) in std::mem::take(&mut self.buffers) {
+            let (size, dir) = (self.size, self.dir);
+            let path = self
+                .paths
+                .entry(key)
+                .or_insert_with(|| dir.join(format!("{size}_{}_{}_{}.tri", key.0, key.1, key.2)));
+            OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(path)?
+                .write_all(&buffer)?;
+        }
+        self.buffered = 0;
+        Ok(())
+    }
+
+    /// Flush every buffer, returning the file of each tile with triangles in it
+    fn finish(mut self) -> io::Result<BTreeMap<TileKey, PathBuf>> {
+        self.flush()?;
+        Ok(self.paths)
+    }
+}
+
+fn write_primitive(out: &mut Vec<u8>, primitive: &Primitive) {
+    let (material_id, vertices, uvs, flags) = match primitive {
+        Primitive::Triangle(t) => (t.material_id, t.vertices, t.uvs, 0),
+        Primitive::Segment(s) => {
+            let [a, b] = s.vertices;
+            let uvs = s.uvs.map(|[a, b]| [a, b, b]);
+            (s.material_id, [a, b, b], uvs, SEGMENT)
+        }
+        Primitive::Point(p) => {
+            out.push(POINT);
+            for c in p.position.iter().chain(p.normal.iter()) {
+                out.extend_from_slice(&c.to_le_bytes());
+            }
+            out.extend_from_slice(p.color.as_slice());
+            return;
+        }
+    };
+    out.push(flags | if uvs.is_some() { HAS_UVS } else { 0 });
+    let material = material_id.map_or(NO_MATERIAL, |id| id as u32);
+    out.extend_from_slice(&material.to_le_bytes());
+    for c in vertices.iter().flat_map(|v| v.iter()) {
+        out.extend_from_slice(&c.to_le_bytes());
+    }
+    let uvs = uvs.unwrap_or([Vector2::zeros(); 3]);
+    for c in uvs.iter().flat_map(|uv| uv.iter()) {
+        out.extend_from_slice(&c.to_le_bytes());
+    }
+}
+
+fn read_primitive(reader: &mut impl Read) -> io::Result<Option<Primitive>> {
+    let mut flags = [0];
+    match reader.read_exact(&mut flags) {
+        Ok(()) => {}
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e),
+    }
+    let flags = flags[0];
+    let mut record = [0; FACE_BYTES];
+    let record = match flags & POINT != 0 {
+        true => &mut record[..POINT_BYTES],
+        false => &mut record[..],
+    };
+    reader.read_exact(record)?;
+    let float = |i: usize| f32::from_le_bytes(record[i..i + 4].try_into().unwrap());
+
+    if flags & POINT != 0 {
+        return Ok(Some(Primitive::Point(Point {
+            position: Vector3::from_fn(|c, _| float(4 * c)),
+            normal: Vector3::from_fn(|c, _| float(12 + 4 * c)),
+            color: Vector4::from_column_slice(&record[24..28]),
+        })));
+    }
+    let material = u32::from_le_bytes(record[0..4].try_into().unwrap());
+    let material_id = (material != NO_MATERIAL).then_some(material as usize);
+    let vertices = [0, 1, 2].map(|v| Vector3::from_fn(|c, _| float(4 + 4 * (3 * v + c))));
+    let uvs = [0, 1, 2].map(|v| Vector2::from_fn(|c, _| float(40 + 4 * (2 * v + c))));
+    let uvs = (flags & HAS_UVS != 0).then_some(uvs);
+    Ok(Some(match flags & SEGMENT != 0 {
+        true => Primitive::Segment(Segment {
+            material_id,
+            vertices: [vertices[0], vertices[1]],
+            uvs: uvs.map(|uvs| [uvs[0], uvs[1]]),
+        }),
+        false => Primitive::Triangle(Triangle {
+            material_id,
+            vertices,
+            uvs,
+        }),
+    }))
+}
+
+/// Scratch directory removed along with everything in it when dropped
+struct TempDir(PathBuf);
+
+impl TempDir {
+    fn new() -> io::Result<Self> {
+        let path = std::env::temp_dir().join(format!("obj2brs-{}", Uuid::new_v4()));
+        fs::create_dir_all(&path)?;
+        Ok(Self(path))
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        if let Err(e) = fs::remove_dir_all(&self.0) {
+            tracing::warn!("Failed to remove temporary tiles in {:?}: {e}", self.0);
+        }
+    }
+}
@@ -0,0 +1,157 @@
+use image::{imageops::FilterType, RgbaImage};
+use nalgebra::{Vector2, Vector4};
+
+/// How texels are combined when looking up a texture color
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Hash, clap::ValueEnum)]
+pub enum TextureFilter {
+    /// Use the single closest texel
+    Nearest,
+    /// Blend the four closest texels
+    Bilinear,
+    /// Blend texels from the mip level whose texel size best matches the voxel size
+    Area,
+}
+
+impl Default for TextureFilter {
+    fn default() -> Self {
+        Self::Nearest
+    }
+}
+
+/// How UV coordinates outside of [0, 1] are mapped onto a texture
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Hash, clap::ValueEnum)]
+pub enum WrapMode {
+    Repeat,
+    Clamp,
+    Mirror,
+}
+
+impl Default for WrapMode {
+    fn default() -> Self {
+        Self::Repeat
+    }
+}
+
+impl WrapMode {
+    /// Map a texel coordinate onto `0..len`
+    pub fn wrap(&self, i: i64, len: u32) -> u32 {
+        let len = len as i64;
+        (match self {
+            Self::Repeat => i.rem_euclid(len),
+            Self::Clamp => i.clamp(0, len - 1),
+            Self::Mirror => {
+                let m = i.rem_euclid(2 * len);
+                if m < len {
+                    m
+                } else {
+                    2 * len - 1 - m
+                }
+            }
+        }) as u32
+    }
+}
+
+/// A texture & its mip chain. The mip chain is only generated when area filtering is requested.
+#[derive(Debug)]
+pub struct Texture<'img> {
+    base: &'img RgbaImage,
+    mips: Vec<RgbaImage>,
+}
+
+impl<'img> Texture<'img> {
+    pub fn new(base: &'img RgbaImage, mipmaps: bool) -> Self {
+        let mut mips = Vec::<RgbaImage>::new();
+        if mipmaps {
+            let (mut w, mut h) = base.dimensions();
+            while w > 1 || h > 1 {
+                w = (w / 2).max(1);
+                h = (h / 2).max(1);
+                let prev = mips.last().unwrap_or(base);
+                mips.push(image::imageops::resize(prev, w, h, FilterType::Triangle));
+            }
+        }
+        Self { base, mips }
+    }
+
+    pub fn level(&self, level: usize) -> &RgbaImage {
+        match level {
+            0 => self.base,
+            l => &self.mips[(l - 1).min(self.mips.len() - 1)],
+        }
+    }
+
+    pub fn levels(&self) -> usize {
+        self.mips.len() + 1
+    }
+}
+
+#[derive(Debug, Default, PartialEq, Eq, Clone, Copy, Hash)]
+pub struct Sampler {
+    pub filter: TextureFilter,
+    pub wrap: WrapMode,
+}
+
+impl Sampler {
+    /// Look up the color of `texture` at `uv`.
+    ///
+    /// `footprint` is the size of the sampled area in UV units (ex. the length of one voxel edge
+    /// mapped into UV space); it's only used for area filtering.
+    pub fn sample(&self, texture: &Texture, uv: Vector2<f32>, footprint: f32) -> Vector4<u8> {
+        match self.filter {
+            TextureFilter::Nearest => self.nearest(texture.level(0), uv),
+            TextureFilter::Bilinear => self.bilinear(texture.level(0), uv),
+            TextureFilter::Area => {
+                let base = texture.level(0);
+                let texels = footprint * base.width().max(base.height()) as f32;
+                let level = if texels > 1. {
+                    (texels.log2().round() as usize).min(texture.levels() - 1)
+                } else {
+                    0
+                };
+                self.bilinear(texture.level(level), uv)
+            }
+        }
+    }
+
+    fn nearest(&self, img: &RgbaImage, uv: Vector2<f32>) -> Vector4<u8> {
+        let x = (uv[0] * img.width() as f32).floor() as i64;
+        // images are stored top to bottom, whereas v points up
+        let y = ((1. - uv[1]) * img.height() as f32).floor() as i64;
+        let p = img
+            .get_pixel(
+                self.wrap.wrap(x, img.width()),
+                self.wrap.wrap(y, img.height()),
+            )
+            .0;
+        Vector4::new(p[0], p[1], p[2], p[3])
+    }
+
+    fn bilinear(&self, img: &RgbaImage, uv: Vector2<f32>) -> Vector4<u8> {
+        // offset by half a texel so that texel centers land on integer coordinates
+        let x = uv[0] * img.width() as f32 - 0.5;
+        let y = (1. - uv[1]) * img.height() as f32 - 0.5;
+        let (x0, y0) = (x.floor(), y.floor());
+        let (fx, fy) = (x - x0, y - y0);
+        let (x0, y0) = (x0 as i64, y0 as i64);
+
+        let texel = |x: i64, y: i64| -> Vector4<f32> {
+            let p = img
+                .get_pixel(
+                    self.wrap.wrap(x, img.width()),
+                    self.wrap.wrap(y, img.height()),
+                )
+                .0;
+            Vector4::new(p[0] as f32, p[1] as f32, p[2] as f32, p[3] as f32)
+        };
+
+        let top = texel(x0, y0) * (1. - fx) + texel(x0 + 1, y0) * fx;
+        let bottom = texel(x0, y0 + 1) * (1. - fx) + texel(x0 + 1, y0 + 1) * fx;
+        let c = top * (1. - fy) + bottom * fy;
+        Vector4::new(
+            c[0].round() as u8,
+            c[1].round() as u8,
+            c[2].round() as u8,
+            c[3].round() as u8,
+        )
+    }
+}
@@ -1,186 +1,746 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 
 use crate::barycentric::interpolate_uv;
 use crate::color::*;
-use crate::intersect::intersect;
-use crate::load::{MeshExt, ModelSet, ObjMaterial, ObjRegistry};
-use crate::octree::{Branches, TreeBody, VoxelTree};
-use crate::BrickType;
+use crate::dda::traverse;
+use crate::intersect::{clip_triangle, intersect, intersect_thin};
+use crate::load::{Model, ModelSet, ObjMaterial, ObjRegistry};
+use crate::octree::child_offset;
+use crate::store::{VoxelStorage, VoxelStore};
+use crate::texture::{Sampler, Texture, TextureFilter};
+use crate::{AlphaMode, BrickType};
 
-use image::RgbaImage;
 use nalgebra::{Matrix4, Point3, Projective3, Vector2, Vector3, Vector4};
 use parry3d::bounding_volume::AABB;
+use rayon::prelude::*;
 
 #[derive(Debug, Copy, Clone)]
 #[repr(C)]
-struct Triangle {
-    material_id: Option<usize>,
-    vertices: [Vector3<f32>; 3],
-    uvs: Option<[Vector2<f32>; 3]>,
+pub struct Triangle {
+    pub material_id: Option<usize>,
+    pub vertices: [Vector3<f32>; 3],
+    pub uvs: Option<[Vector2<f32>; 3]>,
 }
 
-impl ModelSet {
-    pub fn voxelize(
+impl Triangle {
+    /// Normal of the triangle, with a magnitude of twice its area
+    fn normal(&self) -> Vector3<f32> {
+        (self.vertices[1] - self.vertices[0]).cross(&(self.vertices[2] - self.vertices[0]))
+    }
+
+    /// Length in UV space covered by one unit of length on the surface of the triangle
+    fn uv_footprint(&self) -> f32 {
+        match self.uvs {
+            Some(uvs) => {
+                let area = self.normal().magnitude();
+                let e0 = uvs[1] - uvs[0];
+                let e1 = uvs[2] - uvs[0];
+                let uv_area = (e0.x * e1.y - e0.y * e1.x).abs();
+                if area > 0. {
+                    (uv_area / area).sqrt()
+                } else {
+                    0.
+                }
+            }
+            None => 0.,
+        }
+    }
+
+    fn color_at(
         &self,
-        images: &HashMap<String, image::RgbaImage>,
-        transform: &Projective3<f32>,
-    ) -> VoxelTree<Vector4<u8>> {
-        let mut octree = VoxelTree::<Vector4<u8>>::new();
-
-        let aabb = AABB::new_invalid();
-        for model in &self.models {
-            let mesh = &model.mesh;
-            let material = mesh.material_id.map(|id| &self.materials[id]);
-            // the material is applied to every triangle in the mesh, so there's no reason to
-            // bother with it if it's invisible
-            if let Some(ObjMaterial::Color(_, _, _, 0.0)) = material {
+        point: Vector3<f32>,
+        textures: &HashMap<String, Texture>,
+        materials: &[ObjMaterial],
+        sampler: &Sampler,
+    ) -> Option<Vector4<u8>> {
+        let material = &materials[self.material_id?];
+        let uv = interpolate_uv(&self.vertices, &self.uvs, point);
+        let footprint = self.uv_footprint();
+        Some(material_color(material, uv, footprint, textures, sampler))
+    }
+
+    /// Area of the part of the triangle inside the box `half_box` around `center`
+    fn area_within(&self, half_box: f32, center: Vector3<f32>) -> f32 {
+        let [p0, p1, p2] = self.vertices;
+        let polygon = clip_triangle(half_box, center, p0, p1, p2);
+        (2..polygon.len())
+            .map(|i| {
+                let (a, b, c) = (polygon[0], polygon[i - 1], polygon[i]);
+                (b - a).cross(&(c - a)).magnitude() / 2.
+            })
+            .sum()
+    }
+
+    /// Sample colors at stratified points across the part of the triangle within the given box.
+    /// Each sample is weighted by the area it covers.
+    fn supersample(
+        &self,
+        half_box: f32,
+        center: Vector3<f32>,
+        samples: u32,
+        textures: &HashMap<String, Texture>,
+        materials: &[ObjMaterial],
+        sampler: &Sampler,
+    ) -> Vec<(Vector4<u8>, f32)> {
+        let [p0, p1, p2] = self.vertices;
+        let polygon = clip_triangle(half_box, center, p0, p1, p2);
+
+        // the clipped polygon is convex, so a fan is enough to triangulate it
+        let fan = (1..polygon.len().saturating_sub(1))
+            .map(|i| {
+                let (a, b, c) = (polygon[0], polygon[i], polygon[i + 1]);
+                (a, b, c, (b - a).cross(&(c - a)).magnitude() / 2.)
+            })
+            .collect::<Vec<_>>();
+        let total_area: f32 = fan.iter().map(|f| f.3).sum();
+        if total_area <= 0. {
+            // the triangle only grazes the box, so it's sampled once at what was clipped of it
+            let centroid = match polygon.is_empty() {
+                true => (p0 + p1 + p2) / 3.,
+                false => polygon.iter().sum::<Vector3<f32>>() / polygon.len() as f32,
+            };
+            return self
+                .color_at(centroid, textures, materials, sampler)
+                .map(|c| vec![(c, f32::EPSILON)])
+                .unwrap_or_default();
+        }
+
+        let mut res = Vec::with_capacity(samples as usize);
+        for (a, b, c, area) in fan {
+            let n = ((samples as f32 * area / total_area).round() as u32).max(1);
+            // n stratified samples in rows of equal area, mapped onto the triangle with an
+            // area-preserving warp
+            let rows = (n as f32 / (n as f32).sqrt().ceil()).ceil() as u32;
+            for i in 0..rows {
+                let columns = n * (i + 1) / rows - n * i / rows;
+                let weight = area / (rows * columns) as f32;
+                let r = ((i as f32 + 0.5) / rows as f32).sqrt();
+                for j in 0..columns {
+                    let t = (j as f32 + 0.5) / columns as f32;
+                    let point = a * (1. - r) + b * (r * (1. - t)) + c * (r * t);
+                    if let Some(color) = self.color_at(point, textures, materials, sampler) {
+                        res.push((color, weight));
+                    }
+                }
+            }
+        }
+        res
+    }
+}
+
+/// A line segment, or a point if both ends are the same
+#[derive(Debug, Copy, Clone)]
+pub struct Segment {
+    pub material_id: Option<usize>,
+    pub vertices: [Vector3<f32>; 2],
+    pub uvs: Option<[Vector2<f32>; 2]>,
+}
+
+impl Segment {
+    /// Fraction of the way along the segment of the point closest to `point`
+    fn closest(&self, point: Vector3<f32>) -> f32 {
+        let [a, b] = self.vertices;
+        let length = (b - a).norm_squared();
+        match length > 0. {
+            true => ((point - a).dot(&(b - a)) / length).clamp(0., 1.),
+            false => 0.,
+        }
+    }
+
+    fn color_at(
+        &self,
+        t: f32,
+        textures: &HashMap<String, Texture>,
+        materials: &[ObjMaterial],
+        sampler: &Sampler,
+    ) -> Option<Vector4<u8>> {
+        let material = &materials[self.material_id?];
+        let (uv, footprint) = match self.uvs {
+            Some([a, b]) => {
+                let length = (self.vertices[1] - self.vertices[0]).magnitude();
+                let footprint = match length > 0. {
+                    true => (b - a).magnitude() / length,
+                    false => 0.,
+                };
+                (a.lerp(&b, t), footprint)
+            }
+            None => (Vector2::zeros(), 0.),
+        };
+        Some(material_color(material, uv, footprint, textures, sampler))
+    }
+}
+
+/// A point of a point cloud, with its own color
+#[derive(Debug, Copy, Clone)]
+pub struct Point {
+    pub position: Vector3<f32>,
+    pub color: Vector4<u8>,
+    /// Zero if the cloud has no normals
+    pub normal: Vector3<f32>,
+}
+
+/// Color of a material at a point with the given texture coordinates
+fn material_color(
+    material: &ObjMaterial,
+    uv: Vector2<f32>,
+    footprint: f32,
+    textures: &HashMap<String, Texture>,
+    sampler: &Sampler,
+) -> Vector4<u8> {
+    let mut color = Vector4::from(material.color);
+    if let Some(key) = &material.texture {
+        let texel = sampler.sample(&textures[key], uv, footprint);
+        color = color.component_mul(&texel.map(itof));
+    }
+    if let Some(key) = &material.alpha_texture {
+        color.w *= itof(sampler.sample(&textures[key], uv, footprint)[3]);
+    }
+    color.map(ftoi)
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Voxel {
+    pub color: Vector4<u8>,
+    /// Area-weighted average of the normals of the triangles within the voxel
+    pub normal: Vector3<f32>,
+}
+
+/// Which voxels a triangle's surface is made of
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Hash, clap::ValueEnum)]
+pub enum Connectivity {
+    /// Every voxel the triangle touches. Diagonal walls can be up to three voxels thick.
+    Conservative,
+    /// Only the voxels needed to leave no gaps between face-adjacent voxels (6-separating), so
+    /// walls are one voxel thick in any direction
+    Thin,
+}
+
+impl Default for Connectivity {
+    fn default() -> Self {
+        Self::Conservative
+    }
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Hash)]
+pub struct VoxelizeOptions {
+    pub averaging: ColorAveraging,
+    pub sampler: Sampler,
+    /// Number of texture samples taken per triangle in each voxel; 1 samples only the point
+    /// closest to the voxel center
+    pub samples: u32,
+    /// Samples with alpha at or below this value are treated as empty
+    pub alpha_cutoff: u8,
+    pub alpha_mode: AlphaMode,
+    pub connectivity: Connectivity,
+    pub storage: VoxelStorage,
+    /// Diameter in voxels that lines & points are drawn with; they're skipped if `None`
+    pub line_thickness: Option<u32>,
+    /// Voxels with fewer point cloud points than this are discarded as noise
+    pub min_points: u32,
+}
+
+impl Default for VoxelizeOptions {
+    fn default() -> Self {
+        Self {
+            averaging: ColorAveraging::default(),
+            sampler: Sampler::default(),
+            samples: 1,
+            alpha_cutoff: 0,
+            alpha_mode: AlphaMode::default(),
+            connectivity: Connectivity::default(),
+            storage: VoxelStorage::default(),
+            line_thickness: None,
+            min_points: 1,
+        }
+    }
+}
+
+impl ModelSet {
+    /// Models with a visible material
+    fn visible_models(&self) -> impl Iterator<Item = &Model> + '_ {
+        self.models.iter().filter(|model| {
+            let material = model.material_id.map(|id| &self.materials[id]);
+            // the material is applied to every face in the mesh, so there's no reason to bother
+            // with it if it's invisible
+            let invisible = matches!(material, Some(m) if m.color[3] == 0.0);
+            if invisible {
                 tracing::debug!(
                     "Skipping mesh with invisible material: {}.{}",
                     &model.name,
-                    mesh.material_id.as_ref().unwrap()
+                    model.material_id.as_ref().unwrap()
                 );
-                continue;
-            }
-            // prepare & transform vertices
-            let verts = vec![];
-            for v in mesh.vertices() {
-                let pos = &mut v[0..3];
-                let p = transform.transform_point(&Point3::from_slice(pos));
-                pos[0] = p.x;
-                pos[1] = p.y;
-                pos[2] = p.z;
-                // expand aabb
-                aabb.take_point(p);
-                verts.push(v);
             }
-            // voxelize each triangle
-            for tri in mesh.triangles() {
-                let a = verts[tri[0] as usize];
-                let b = verts[tri[1] as usize];
-                let c = verts[tri[2] as usize];
-            }
-        }
+            !invisible
+        })
+    }
 
-        let floor_min = Vector3::<isize>::new(
-            min[0].floor() as isize - 1,
-            min[1].floor() as isize - 1,
-            min[2].floor() as isize - 1,
-        );
-        let ceil_max = Vector3::<isize>::new(
-            max[0].ceil() as isize + 1,
-            max[1].ceil() as isize + 1,
-            max[2].ceil() as isize + 1,
-        );
+    /// Triangles of every visible mesh in the set
+    pub fn triangles(&self) -> impl Iterator<Item = Triangle> + '_ {
+        self.visible_models().flat_map(|model| {
+            model.mesh.triangles().map(|triangle| Triangle {
+                material_id: model.material_id,
+                vertices: triangle.positions(),
+                uvs: triangle.uvs(),
+            })
+        })
+    }
+
+    /// Lines & points of every visible mesh in the set
+    pub fn segments(&self) -> impl Iterator<Item = Segment> + '_ {
+        self.visible_models().flat_map(|model| {
+            model.mesh.lines().map(|line| {
+                let ends = [0, line.len() - 1];
+                let uvs = ends.map(|c| line.uv(c));
+                Segment {
+                    material_id: model.material_id,
+                    vertices: ends.map(|c| line.position(c)),
+                    uvs: match uvs {
+                        [Some(a), Some(b)] => Some([a, b]),
+                        _ => None,
+                    },
+                }
+            })
+        })
+    }
+
+    /// Vertices of every point cloud in the set
+    pub fn points(&self) -> impl Iterator<Item = Point> + '_ {
+        self.visible_models()
+            .filter(|model| !model.mesh.has_faces())
+            .flat_map(|model| {
+                let mesh = &model.mesh;
+                (0..mesh.vertex_count()).map(move |i| Point {
+                    position: mesh.position(i),
+                    color: mesh
+                        .color(i)
+                        .map_or(Vector4::repeat(255), |c| c.push(1.).map(ftoi)),
+                    normal: mesh.normal(i).unwrap_or_else(Vector3::zeros),
+                })
+            })
+    }
+
+    /// Voxelize every model in the set into `store`, with meshes already in voxel space
+    pub fn voxelize(
+        &self,
+        images: &HashMap<String, image::RgbaImage>,
+        options: &VoxelizeOptions,
+        store: &mut dyn VoxelStore<Voxel>,
+    ) {
+        let textures = load_textures(images, options);
 
-        while !octree.contains_bounds(floor_min) || !octree.contains_bounds(ceil_max) {
-            octree.size += 1;
+        let mut aabb = AABB::new_invalid();
+        let triangles = self
+            .triangles()
+            .inspect(|t| {
+                t.vertices
+                    .iter()
+                    .for_each(|v| aabb.take_point(Point3::from(*v)))
+            })
+            .collect::<Vec<_>>();
+        let segments = match options.line_thickness {
+            Some(_) => self
+                .segments()
+                .inspect(|s| {
+                    s.vertices
+                        .iter()
+                        .for_each(|v| aabb.take_point(Point3::from(*v)))
+                })
+                .collect::<Vec<_>>(),
+            None => vec![],
+        };
+        let points = self
+            .points()
+            .inspect(|p| aabb.take_point(Point3::from(p.position)))
+            .collect::<Vec<_>>();
+        if triangles.is_empty() && segments.is_empty() && points.is_empty() {
+            return;
         }
 
-        let mask = 1 << octree.size; // mask = 1ᵗ, where `t` is the size of the octree
+        let floor_min = aabb.mins.coords.map(|c| c.floor() as isize - 1);
+        let ceil_max = aabb.maxs.coords.map(|c| c.ceil() as isize + 1);
+        let size = (ceil_max - floor_min).max().max(2) as usize;
+        let size = size.next_power_of_two() as isize;
 
-        recursive_voxelize(
-            &mut octree.contents,
-            mask,
+        voxelize_triangles(
             triangles,
-            images,
+            floor_min,
+            size,
+            &textures,
             &self.materials,
+            options,
+            store,
         );
-
-        octree
+        voxelize_segments(&segments, None, &textures, &self.materials, options, store);
+        voxelize_points(&points, None, options, store);
     }
 }
 
-impl ObjRegistry {
-    pub fn voxelize(&self, scale: f32, bricktype: BrickType) -> VoxelTree<Vector4<u8>> {
-        todo!()
+/// Prepare loaded images for sampling
+pub fn load_textures<'img>(
+    images: &'img HashMap<String, image::RgbaImage>,
+    options: &VoxelizeOptions,
+) -> HashMap<String, Texture<'img>> {
+    images
+        .iter()
+        .map(|(key, img)| {
+            (
+                key.clone(),
+                Texture::new(img, options.sampler.filter == TextureFilter::Area),
+            )
+        })
+        .collect()
+}
+
+/// Voxelize the triangles within the cube spanning `size` voxels from `origin` into `store`.
+/// `size` must be a power of two; triangles may extend past the cube, but only voxels inside it
+/// are produced.
+pub fn voxelize_triangles(
+    mut triangles: Vec<Triangle>,
+    origin: Vector3<isize>,
+    size: isize,
+    textures: &HashMap<String, Texture>,
+    materials: &[ObjMaterial],
+    options: &VoxelizeOptions,
+    store: &mut dyn VoxelStore<Voxel>,
+) {
+    // Triangles are kept relative to the center of the node being voxelized
+    let center = origin.map(|c| (c + size / 2) as f32);
+    for triangle in &mut triangles {
+        for v in &mut triangle.vertices {
+            *v -= center;
+        }
+    }
+
+    let mut voxels = vec![];
+    recursive_voxelize(
+        &mut voxels,
+        origin,
+        size,
+        triangles,
+        textures,
+        materials,
+        options,
+    );
+    for (pos, voxel) in voxels {
+        store.insert(pos, voxel);
     }
 }
 
-fn recursive_voxelize<'a>(
-    branches: &'a mut Branches<Vector4<u8>>,
-    mask: isize,
-    vector: Vec<Triangle>,
-    images: &HashMap<String, RgbaImage>,
+/// Draw lines & points `options.line_thickness` voxels across into `store`, optionally only
+/// within the cube spanning `size` voxels from `origin`. Voxels already filled by triangles are
+/// left as they are.
+pub fn voxelize_segments(
+    segments: &[Segment],
+    bounds: Option<(Vector3<isize>, isize)>,
+    textures: &HashMap<String, Texture>,
     materials: &[ObjMaterial],
+    options: &VoxelizeOptions,
+    store: &mut dyn VoxelStore<Voxel>,
 ) {
-    let m = mask >> 1;
-    let half_box = (2 * m + ((m == 0) as isize)) as f32 / 2.;
-
-    for (i, branch) in branches.iter_mut().enumerate() {
-        if let TreeBody::Empty = branch {
-            let center = Vector3::<f32>::new(
-                half_box * (2 * ((i & 4) > 0) as isize - 1) as f32,
-                half_box * (2 * ((i & 2) > 0) as isize - 1) as f32,
-                half_box * (2 * ((i & 1) > 0) as isize - 1) as f32,
-            );
-
-            let mut triangles = Vec::<Triangle>::new();
-            let mut colors = Vec::<Vector4<u8>>::new();
-
-            for triangle in &vector {
-                match intersect(
-                    half_box,
-                    center,
-                    triangle.vertices[0],
-                    triangle.vertices[1],
-                    triangle.vertices[2],
-                ) {
-                    Some(intersection) => {
-                        // Only calculate colors if in root level
-                        if m == 0 {
-                            if let Some(id) = triangle.material_id {
-                                let uv =
-                                    interpolate_uv(&triangle.vertices, &triangle.uvs, intersection);
-                                let mat = &materials[id];
-
-                                let c = match mat {
-                                    ObjMaterial::Color(r, g, b, a) => [
-                                        (255.0 * r) as u8,
-                                        (255.0 * g) as u8,
-                                        (255.0 * b) as u8,
-                                        (255.0 * a) as u8,
-                                    ],
-                                    ObjMaterial::ImageKey(img) => {
-                                        let img = images[img];
-                                        let u = ((uv[0] - uv[0].floor()) * (img.width() - 1) as f32)
-                                            as u32;
-                                        let v = ((1. - uv[1] + uv[1].floor())
-                                            * (img.height() - 1) as f32)
-                                            as u32;
-                                        img.get_pixel(u, v).0
-                                    }
-                                };
-
-                                if c[3] == 0 {
-                                    continue;
-                                } // If alpha is zero, skeedaddle
-                                colors.push(Vector4::<u8>::new(c[0], c[1], c[2], c[3]));
-                            }
+    let thickness = match options.line_thickness {
+        Some(thickness) => thickness.max(1),
+        None => return,
+    };
+    let radius = thickness as f32 / 2.;
+    let reach = (radius - 0.5).ceil() as isize;
+    let in_bounds = |pos: &Vector3<isize>| match bounds {
+        Some((origin, size)) => (0..3).all(|i| (origin[i]..origin[i] + size).contains(&pos[i])),
+        None => true,
+    };
+
+    let mut voxels = HashMap::<Vector3<isize>, Vec<(Vector4<u8>, f32)>>::new();
+    for segment in segments {
+        let [a, b] = segment.vertices;
+        let mut drawn = HashSet::new();
+        for voxel in traverse(a, b) {
+            // the voxels the segment passes through are always drawn, so thin lines stay
+            // connected, along with any others within the line's radius
+            for x in -reach..=reach {
+                for y in -reach..=reach {
+                    for z in -reach..=reach {
+                        let pos = voxel + Vector3::new(x, y, z);
+                        let center = pos.map(|c| c as f32 + 0.5);
+                        let t = segment.closest(center);
+                        let distance = (a.lerp(&b, t) - center).magnitude();
+                        if (pos != voxel && distance > radius) || !in_bounds(&pos) {
+                            continue;
                         }
+                        if !drawn.insert(pos) {
+                            continue;
+                        }
+                        let color = segment.color_at(t, textures, materials, &options.sampler);
+                        let samples = match color {
+                            Some(c) if c[3] <= options.alpha_cutoff => continue,
+                            Some(mut c) => {
+                                if options.alpha_mode == AlphaMode::Cutout {
+                                    c[3] = 255;
+                                }
+                                vec![(c, 1.)]
+                            }
+                            None => vec![],
+                        };
+                        voxels.entry(pos).or_default().extend(samples);
                     }
-                    None => continue,
                 }
+            }
+        }
+    }
 
-                let mut cloned_triangle = *triangle;
-                cloned_triangle.vertices[0] -= center;
-                cloned_triangle.vertices[1] -= center;
-                cloned_triangle.vertices[2] -= center;
+    for (pos, colors) in voxels {
+        if store.get(pos).is_some() {
+            continue;
+        }
+        store.insert(
+            pos,
+            Voxel {
+                color: weighted_average(colors, options.averaging),
+                normal: Vector3::zeros(),
+            },
+        );
+    }
+}
 
-                triangles.push(cloned_triangle);
-            }
+/// Bin point cloud points into the voxels they fall in, optionally only within the cube spanning
+/// `size` voxels from `origin`, averaging their colors & normals. Voxels with fewer than
+/// `options.min_points` points are dropped, and voxels already filled are left as they are.
+pub fn voxelize_points(
+    points: &[Point],
+    bounds: Option<(Vector3<isize>, isize)>,
+    options: &VoxelizeOptions,
+    store: &mut dyn VoxelStore<Voxel>,
+) {
+    let in_bounds = |pos: &Vector3<isize>| match bounds {
+        Some((origin, size)) => (0..3).all(|i| (origin[i]..origin[i] + size).contains(&pos[i])),
+        None => true,
+    };
+
+    let mut voxels = HashMap::<Vector3<isize>, (Vec<(Vector4<u8>, f32)>, Vector3<f32>)>::new();
+    for point in points {
+        let pos = point.position.map(|c| c.floor() as isize);
+        if !in_bounds(&pos) {
+            continue;
+        }
+        let (colors, normal) = voxels.entry(pos).or_default();
+        colors.push((point.color, 1.));
+        *normal += point.normal;
+    }
+
+    let mut dropped = 0;
+    for (pos, (colors, normal)) in voxels {
+        if colors.len() < options.min_points as usize {
+            dropped += 1;
+            continue;
+        }
+        if store.get(pos).is_some() {
+            continue;
+        }
+        store.insert(
+            pos,
+            Voxel {
+                color: weighted_average(colors, options.averaging),
+                normal: normal
+                    .try_normalize(f32::EPSILON)
+                    .unwrap_or_else(Vector3::zeros),
+            },
+        );
+    }
+    if dropped > 0 {
+        tracing::debug!("Dropped {dropped} voxels with too few points");
+    }
+}
+
+/// Transform from model space to voxel space, with voxels the shape of `bricktype`
+pub fn voxel_transform(scale: f32, bricktype: BrickType) -> Projective3<f32> {
+    Projective3::from_matrix_unchecked(
+        Matrix4::new_scaling(scale) * bricktype.scale().to_homogeneous(),
+    )
+}
+
+impl ObjRegistry {
+    /// Voxelize every loaded model set into one store, with voxels the shape of `bricktype`.
+    /// Meshes are transformed into voxel space in place.
+    pub fn voxelize(
+        &mut self,
+        scale: f32,
+        bricktype: BrickType,
+        options: &VoxelizeOptions,
+    ) -> Box<dyn VoxelStore<Voxel>> {
+        let transform = voxel_transform(scale, bricktype);
+        let mut store = options.storage.create();
+        for set in &mut self.model_sets {
+            set.transform(&transform);
+            set.voxelize(&self.images, options, store.as_mut());
+        }
+        store
+    }
+}
+
+/// Octree nodes at least this many voxels across have their octants voxelized in parallel
+const PARALLEL_SIZE: isize = 32;
 
-            if triangles.is_empty() {
-                continue;
+/// Voxelize the node spanning `size` voxels from `origin` into `out`, in Morton order, where
+/// `vector` holds the triangles touching the node relative to its center
+fn recursive_voxelize(
+    out: &mut Vec<(Vector3<isize>, Voxel)>,
+    origin: Vector3<isize>,
+    size: isize,
+    vector: Vec<Triangle>,
+    textures: &HashMap<String, Texture>,
+    materials: &[ObjMaterial],
+    options: &VoxelizeOptions,
+) {
+    if size >= PARALLEL_SIZE {
+        // Collecting keeps the octants in order, so the result doesn't depend on scheduling
+        let octants = (0..8)
+            .into_par_iter()
+            .map(|i| {
+                let mut octant = vec![];
+                voxelize_octant(
+                    &mut octant,
+                    i,
+                    origin,
+                    size,
+                    &vector,
+                    textures,
+                    materials,
+                    options,
+                );
+                octant
+            })
+            .collect::<Vec<_>>();
+        out.extend(octants.into_iter().flatten());
+    } else {
+        for i in 0..8 {
+            voxelize_octant(out, i, origin, size, &vector, textures, materials, options);
+        }
+    }
+}
+
+/// Voxelize the `i`th child of the node spanning `size` voxels from `origin`
+fn voxelize_octant(
+    out: &mut Vec<(Vector3<isize>, Voxel)>,
+    i: usize,
+    origin: Vector3<isize>,
+    size: isize,
+    vector: &[Triangle],
+    textures: &HashMap<String, Texture>,
+    materials: &[ObjMaterial],
+    options: &VoxelizeOptions,
+) {
+    let half = size / 2;
+    let half_box = half as f32 / 2.;
+    let leaf = half == 1;
+
+    let offset = child_offset(i);
+    let center = offset.map(|o| half_box * (2 * o - 1) as f32);
+
+    let mut triangles = Vec::<Triangle>::new();
+    let mut colors = Vec::<(Vector4<u8>, f32)>::new();
+    let mut normal = Vector3::<f32>::zeros();
+
+    for triangle in vector {
+        match intersect(
+            half_box,
+            center,
+            triangle.vertices[0],
+            triangle.vertices[1],
+            triangle.vertices[2],
+        ) {
+            Some(_)
+                if leaf
+                    && options.connectivity == Connectivity::Thin
+                    && !intersect_thin(
+                        half_box,
+                        center,
+                        triangle.vertices[0],
+                        triangle.vertices[1],
+                        triangle.vertices[2],
+                    ) =>
+            {
+                continue
             }
-            if m != 0 {
-                // Not yet at root level, keep on recursing...
-                *branch = TreeBody::Branch(Box::new(TreeBody::empty()));
-                if let TreeBody::Branch(b) = branch {
-                    recursive_voxelize(b, m, triangles, images, materials);
+            Some(intersection) => {
+                // Only calculate colors if in root level
+                if leaf && triangle.material_id.is_some() {
+                    let samples = if options.samples > 1 {
+                        triangle.supersample(
+                            half_box,
+                            center,
+                            options.samples,
+                            textures,
+                            materials,
+                            &options.sampler,
+                        )
+                    } else {
+                        triangle
+                            .color_at(intersection, textures, materials, &options.sampler)
+                            .map(|c| vec![(c, 1.)])
+                            .unwrap_or_default()
+                    };
+
+                    let len = colors.len();
+                    colors.extend(
+                        samples
+                            .into_iter()
+                            .filter(|(c, _)| c[3] > options.alpha_cutoff)
+                            .map(|(mut c, w)| {
+                                if options.alpha_mode == AlphaMode::Cutout {
+                                    c[3] = 255;
+                                }
+                                (c, w)
+                            }),
+                    );
+                    if colors.len() == len {
+                        continue;
+                    } // If alpha is below the cutoff, skeedaddle
                 }
-            } else {
-                *branch = TreeBody::Leaf(hsv2rgb(hsv_average(&colors)));
             }
+            None => continue,
+        }
+
+        if leaf {
+            // Weighted by the area inside the voxel, so a triangle barely poking in can't outweigh
+            // the surface it's really on. Triangles only touching the voxel keep a sliver of
+            // weight, to still give it a normal.
+            let area = triangle.area_within(half_box, center);
+            normal += triangle
+                .normal()
+                .try_normalize(f32::EPSILON)
+                .unwrap_or_default()
+                * (area + f32::EPSILON);
         }
+
+        let mut cloned_triangle = *triangle;
+        cloned_triangle.vertices[0] -= center;
+        cloned_triangle.vertices[1] -= center;
+        cloned_triangle.vertices[2] -= center;
+
+        triangles.push(cloned_triangle);
+    }
+
+    if triangles.is_empty() {
+        return;
+    }
+    if !leaf {
+        // Not yet at root level, keep on recursing...
+        recursive_voxelize(
+            out,
+            origin + offset * half,
+            half,
+            triangles,
+            textures,
+            materials,
+            options,
+        );
+    } else {
+        out.push((
+            origin + offset,
+            Voxel {
+                color: weighted_average(colors, options.averaging),
+                normal: normal
+                    .try_normalize(f32::EPSILON)
+                    .unwrap_or_else(Vector3::zeros),
+            },
+        ));
     }
 }